@@ -0,0 +1,487 @@
+//! A client for the parts of the CircleCI API that add detail to the spans
+//! of completed jobs, which the webhook payloads don't carry.
+
+use std::{fmt, future::Future, time::Duration};
+
+use chrono::{DateTime, FixedOffset};
+use reqwest::{StatusCode, Url};
+use serde::{de::DeserializeOwned, Deserialize};
+use thiserror::Error;
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::payload::JobCompleted;
+
+pub const DEFAULT_BASE_URL: &str = "https://circleci.com/";
+
+/// Lists longer than this many pages are cut short.
+const MAX_PAGES: usize = 20;
+
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("request to {0} failed: {1}")]
+    Request(Url, reqwest::Error),
+    #[error("request to {0} failed with status {1}")]
+    Status(Url, StatusCode),
+    #[error("invalid url: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("gave up after {0:?}")]
+    Deadline(Duration),
+}
+
+/// Talks to CircleCI, or to anything that answers like it at `base_url`.
+pub struct Client {
+    http: reqwest::Client,
+    base_url: Url,
+    token: Option<String>,
+    timeout: Duration,
+    steps: bool,
+    tests: bool,
+    test_spans: TestSpans,
+    artifacts: Option<usize>,
+    dependencies: bool,
+}
+
+impl Client {
+    /// All requests for one job together may take at most `timeout`.
+    pub fn new(
+        base_url: Url,
+        token: Option<String>,
+        timeout: Duration,
+    ) -> Result<Client, reqwest::Error> {
+        Ok(Client {
+            http: reqwest::Client::builder().timeout(timeout).build()?,
+            base_url,
+            token,
+            timeout,
+            steps: false,
+            tests: false,
+            test_spans: TestSpans::default(),
+            artifacts: None,
+            dependencies: false,
+        })
+    }
+
+    /// Fetches the steps of completed jobs.
+    pub fn with_steps(mut self, steps: bool) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    /// Fetches the test results of completed jobs, and selects the tests that
+    /// get a span of their own.
+    pub fn with_tests(mut self, tests: bool, test_spans: TestSpans) -> Self {
+        self.tests = tests;
+        self.test_spans = test_spans;
+        self
+    }
+
+    /// Lists the artifacts of completed jobs, keeping at most `max_artifacts`.
+    pub fn with_artifacts(mut self, artifacts: bool, max_artifacts: usize) -> Self {
+        self.artifacts = artifacts.then_some(max_artifacts);
+        self
+    }
+
+    /// Looks up the jobs that completed jobs required.
+    pub fn with_dependencies(mut self, dependencies: bool) -> Self {
+        self.dependencies = dependencies;
+        self
+    }
+
+    /// Everything enabled about the job of `event`, fetched concurrently.
+    /// What can't be fetched within the timeout is logged and left out, so
+    /// the job span is exported regardless.
+    pub async fn job_details(&self, event: &JobCompleted) -> JobDetails {
+        let deadline = Instant::now() + self.timeout;
+        let (slug, number, job_id) = (&event.project.slug, event.job.number, &event.job.id);
+        let (steps, tests, artifacts, workflow_jobs) = tokio::join!(
+            self.fetch(
+                self.steps,
+                deadline,
+                "steps",
+                job_id,
+                self.steps(slug, number)
+            ),
+            self.fetch(
+                self.tests,
+                deadline,
+                "tests",
+                job_id,
+                self.tests(slug, number)
+            ),
+            self.fetch(
+                self.artifacts.is_some(),
+                deadline,
+                "artifacts",
+                job_id,
+                self.artifacts(slug, number)
+            ),
+            self.fetch(
+                self.dependencies,
+                deadline,
+                "jobs",
+                &event.workflow.id,
+                self.workflow_jobs(&event.workflow.id)
+            ),
+        );
+
+        let mut details = JobDetails {
+            steps: steps.unwrap_or_default(),
+            tests,
+            ..Default::default()
+        };
+        if self.tests {
+            details.test_spans = self.test_spans;
+        }
+        if let (Some(mut artifacts), Some(max_artifacts)) = (artifacts, self.artifacts) {
+            details.artifact_count = Some(artifacts.len());
+            artifacts.truncate(max_artifacts);
+            details.artifacts = artifacts;
+        }
+        if let Some(jobs) = workflow_jobs {
+            details.dependencies = dependencies(jobs, job_id);
+        }
+        details
+    }
+
+    /// Runs `request` if `enabled`, giving up at `deadline`.
+    /// All jobs of a completed workflow, taking at most `timeout` like the
+    /// details of a job.
+    pub async fn completed_workflow_jobs(&self, workflow_id: &Uuid) -> Option<Vec<WorkflowJob>> {
+        let deadline = Instant::now() + self.timeout;
+        self.fetch(
+            true,
+            deadline,
+            "jobs",
+            workflow_id,
+            self.workflow_jobs(workflow_id),
+        )
+        .await
+    }
+
+    async fn fetch<T>(
+        &self,
+        enabled: bool,
+        deadline: Instant,
+        what: &str,
+        id: &Uuid,
+        request: impl Future<Output = Result<T, ApiError>>,
+    ) -> Option<T> {
+        if !enabled {
+            return None;
+        }
+        let result = timeout_at(deadline, request)
+            .await
+            .unwrap_or(Err(ApiError::Deadline(self.timeout)));
+        match result {
+            Ok(value) => Some(value),
+            Err(error) => {
+                warn!("Failed to fetch the {} of {}: {}", what, id, error);
+                None
+            }
+        }
+    }
+
+    /// The steps of a job, from the v1.1 API as v2 doesn't have them.
+    pub async fn steps(&self, project_slug: &str, job_number: i64) -> Result<Vec<Step>, ApiError> {
+        let path = format!("api/v1.1/project/{}/{}", project_slug, job_number);
+        Ok(self.get::<JobV1>(self.base_url.join(&path)?).await?.steps)
+    }
+
+    /// The results a job stored with `store_test_results`.
+    pub async fn tests(
+        &self,
+        project_slug: &str,
+        job_number: i64,
+    ) -> Result<Vec<TestResult>, ApiError> {
+        self.get_all(&format!(
+            "api/v2/project/{}/{}/tests",
+            project_slug, job_number
+        ))
+        .await
+    }
+
+    /// The files a job stored with `store_artifacts`.
+    pub async fn artifacts(
+        &self,
+        project_slug: &str,
+        job_number: i64,
+    ) -> Result<Vec<Artifact>, ApiError> {
+        self.get_all(&format!(
+            "api/v2/project/{}/{}/artifacts",
+            project_slug, job_number
+        ))
+        .await
+    }
+
+    /// All jobs of a workflow, including those that didn't run.
+    pub async fn workflow_jobs(&self, workflow_id: &Uuid) -> Result<Vec<WorkflowJob>, ApiError> {
+        self.get_all(&format!("api/v2/workflow/{}/job", workflow_id))
+            .await
+    }
+
+    /// The items of all pages of a v2 list.
+    async fn get_all<T: DeserializeOwned>(&self, path: &str) -> Result<Vec<T>, ApiError> {
+        let mut items = vec![];
+        let mut page_token: Option<String> = None;
+        for _ in 0..MAX_PAGES {
+            let mut url = self.base_url.join(path)?;
+            if let Some(page_token) = &page_token {
+                url.query_pairs_mut().append_pair("page-token", page_token);
+            }
+            let page: Page<T> = self.get(url).await?;
+            items.extend(page.items);
+            page_token = page.next_page_token;
+            if page_token.is_none() {
+                return Ok(items);
+            }
+        }
+        warn!("Stopped fetching {} after {} pages", path, MAX_PAGES);
+        Ok(items)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, url: Url) -> Result<T, ApiError> {
+        debug!("Fetching {}", url);
+        let mut request = self.http.get(url.clone());
+        if let Some(token) = &self.token {
+            request = request.header("Circle-Token", token);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|error| ApiError::Request(url.clone(), error))?;
+        if !response.status().is_success() {
+            return Err(ApiError::Status(url, response.status()));
+        }
+        response
+            .json()
+            .await
+            .map_err(|error| ApiError::Request(url, error))
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("base_url", &self.base_url.as_str())
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("timeout", &self.timeout)
+            .field("steps", &self.steps)
+            .field("tests", &self.tests)
+            .field("test_spans", &self.test_spans)
+            .field("artifacts", &self.artifacts)
+            .field("dependencies", &self.dependencies)
+            .finish()
+    }
+}
+
+/// What the API adds to a completed job.
+#[derive(Debug, Default)]
+pub struct JobDetails {
+    pub steps: Vec<Step>,
+    /// `None` if not fetched, as opposed to a job without test results.
+    pub tests: Option<Vec<TestResult>>,
+    pub test_spans: TestSpans,
+    /// The first artifacts, up to the configured maximum.
+    pub artifacts: Vec<Artifact>,
+    /// How many artifacts the job has, if they were listed.
+    pub artifact_count: Option<usize>,
+    /// The jobs of the workflow that this job required.
+    pub dependencies: Vec<WorkflowJob>,
+    /// How much later the job could have finished without delaying its
+    /// workflow, see [`crate::critical_path::analyze`].
+    pub slack: Option<chrono::Duration>,
+}
+
+#[derive(Deserialize)]
+struct Page<T> {
+    items: Vec<T>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JobV1 {
+    #[serde(default)]
+    steps: Vec<Step>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Step {
+    pub name: String,
+    /// One per parallel node the step ran on.
+    #[serde(default)]
+    pub actions: Vec<Action>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Action {
+    /// The parallel node.
+    pub index: i64,
+    /// The position of the step in the job.
+    pub step: i64,
+    pub status: String,
+    pub exit_code: Option<i64>,
+    pub start_time: Option<DateTime<FixedOffset>>,
+    pub end_time: Option<DateTime<FixedOffset>>,
+    #[serde(default)]
+    pub background: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Artifact {
+    pub path: String,
+    pub node_index: i64,
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WorkflowJob {
+    pub id: Uuid,
+    /// Missing for approvals and jobs that didn't run.
+    pub job_number: Option<i64>,
+    pub name: String,
+    /// The ids of the jobs this one required.
+    #[serde(default)]
+    pub dependencies: Vec<Uuid>,
+    pub status: String,
+    pub started_at: Option<DateTime<FixedOffset>>,
+    pub stopped_at: Option<DateTime<FixedOffset>>,
+}
+
+/// The jobs that `job_id` required, in the order it lists them.
+fn dependencies(jobs: Vec<WorkflowJob>, job_id: &Uuid) -> Vec<WorkflowJob> {
+    let Some(job) = jobs.iter().find(|job| &job.id == job_id) else {
+        return vec![];
+    };
+    job.dependencies
+        .iter()
+        .filter_map(|id| jobs.iter().find(|job| &job.id == id))
+        .cloned()
+        .collect()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TestResult {
+    pub name: String,
+    #[serde(default)]
+    pub classname: String,
+    pub file: Option<String>,
+    /// `success`, `failure` or `skipped`.
+    pub result: String,
+    pub message: Option<String>,
+    /// In seconds.
+    #[serde(default)]
+    pub run_time: f64,
+}
+
+impl TestResult {
+    pub fn failed(&self) -> bool {
+        !matches!(self.result.as_str(), "success" | "skipped")
+    }
+
+    pub fn skipped(&self) -> bool {
+        self.result == "skipped"
+    }
+}
+
+/// Which tests get a span of their own, below the job span.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TestSpans {
+    pub failed: bool,
+    /// Tests that took longer than this many seconds.
+    pub slower_than: Option<f64>,
+}
+
+impl TestSpans {
+    pub fn includes(&self, test: &TestResult) -> bool {
+        (self.failed && test.failed())
+            || self
+                .slower_than
+                .is_some_and(|slower_than| test.run_time > slower_than)
+    }
+}
+
+#[cfg(test)]
+mod steps_tests {
+    use super::JobV1;
+
+    #[test]
+    fn test_steps() {
+        let job: JobV1 =
+            serde_json::from_str(include_str!("../testdata/api/job-details.json")).unwrap();
+        assert_eq!(job.steps[0].name, "Spin up environment");
+        let action = &job.steps[2].actions[1];
+        assert_eq!(action.index, 1);
+        assert_eq!(action.step, 102);
+        assert_eq!(action.exit_code, Some(0));
+    }
+
+    #[test]
+    fn test_without_steps() {
+        let job: JobV1 = serde_json::from_str(r#"{"build_num": 10}"#).unwrap();
+        assert!(job.steps.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests_tests {
+    use super::{Page, TestResult, TestSpans};
+
+    #[test]
+    fn test_results() {
+        let page: Page<TestResult> =
+            serde_json::from_str(include_str!("../testdata/api/tests.json")).unwrap();
+        assert_eq!(page.next_page_token.as_deref(), Some("2"));
+        let failed: Vec<_> = page.items.iter().filter(|test| test.failed()).collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].name, "test_invalid_signature");
+    }
+
+    #[test]
+    fn test_spans() {
+        let page: Page<TestResult> =
+            serde_json::from_str(include_str!("../testdata/api/tests.json")).unwrap();
+        let count = |spans: TestSpans| page.items.iter().filter(|t| spans.includes(t)).count();
+        assert_eq!(count(TestSpans::default()), 0);
+        let failed = TestSpans {
+            failed: true,
+            slower_than: None,
+        };
+        assert_eq!(count(failed), 1);
+        let slow = TestSpans {
+            failed: false,
+            slower_than: Some(1.0),
+        };
+        assert_eq!(count(slow), 2);
+    }
+}
+
+#[cfg(test)]
+mod workflow_jobs_tests {
+    use uuid::Uuid;
+
+    use super::{dependencies, Page, WorkflowJob};
+
+    fn jobs() -> Vec<WorkflowJob> {
+        let page: Page<WorkflowJob> =
+            serde_json::from_str(include_str!("../testdata/api/workflow-jobs.json")).unwrap();
+        page.items
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let job_id = Uuid::parse_str("20e45d7e-e4a7-4aa3-8f92-fd6d9d01da75").unwrap();
+        let names: Vec<_> = dependencies(jobs(), &job_id)
+            .into_iter()
+            .map(|job| job.name)
+            .collect();
+        assert_eq!(names, ["checkout", "rust/fmt"]);
+    }
+
+    #[test]
+    fn test_unknown_job() {
+        assert!(dependencies(jobs(), &Uuid::nil()).is_empty());
+    }
+}
@@ -0,0 +1,324 @@
+//! Critical-path analysis of workflows. The spans of completed jobs are held
+//! back until their workflow completes, so that the jobs on its critical path
+//! can be marked once the whole dependency graph is known.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Mutex,
+    time::{Duration as StdDuration, Instant},
+};
+
+use chrono::Duration;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    api::{JobDetails, WorkflowJob},
+    payload::WebhookPayload,
+};
+
+pub const DEFAULT_CAPACITY: usize = 1_000;
+pub const DEFAULT_MAX_AGE: StdDuration = StdDuration::from_secs(60 * 60);
+
+/// How many completed workflows are remembered, to tell late jobs.
+const COMPLETED_CAPACITY: usize = 10_000;
+
+/// A `JobCompleted` payload waiting for its workflow to complete.
+pub type HeldJob = (WebhookPayload, JobDetails);
+
+#[derive(Debug)]
+pub struct CriticalPath {
+    pending: Mutex<PendingWorkflows>,
+}
+
+impl Default for CriticalPath {
+    fn default() -> Self {
+        CriticalPath::new(DEFAULT_CAPACITY, DEFAULT_MAX_AGE)
+    }
+}
+
+impl CriticalPath {
+    /// Holds back at most `capacity` jobs, for at most `max_age`. Past that,
+    /// the jobs of the oldest workflows are given up on and exported unmarked.
+    pub fn new(capacity: usize, max_age: StdDuration) -> CriticalPath {
+        CriticalPath {
+            pending: Mutex::new(PendingWorkflows {
+                capacity,
+                max_age,
+                held: 0,
+                order: VecDeque::new(),
+                jobs: HashMap::new(),
+                completed: VecDeque::new(),
+                completed_ids: HashSet::new(),
+            }),
+        }
+    }
+
+    /// Holds back a job of `workflow_id`, returning the jobs to export now:
+    /// those given up on, and the job itself if its workflow has completed
+    /// already.
+    pub fn hold(&self, workflow_id: Uuid, job: HeldJob) -> Vec<HeldJob> {
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(workflow_id, job, Instant::now())
+    }
+
+    /// The held jobs of a completed workflow, followed by those given up on.
+    pub fn release(&self, workflow_id: &Uuid) -> Vec<HeldJob> {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(workflow_id, Instant::now())
+    }
+
+    /// All held jobs, to export them before shutting down.
+    pub fn drain(&self) -> Vec<HeldJob> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.held = 0;
+        pending.order.clear();
+        pending.jobs.drain().flat_map(|(_, jobs)| jobs).collect()
+    }
+}
+
+#[derive(Debug)]
+struct PendingWorkflows {
+    capacity: usize,
+    max_age: StdDuration,
+    /// The number of jobs in `jobs`.
+    held: usize,
+    /// The workflows with held jobs, by when their first job was held.
+    order: VecDeque<(Uuid, Instant)>,
+    jobs: HashMap<Uuid, Vec<HeldJob>>,
+    completed: VecDeque<Uuid>,
+    completed_ids: HashSet<Uuid>,
+}
+
+impl PendingWorkflows {
+    fn insert(&mut self, workflow_id: Uuid, job: HeldJob, now: Instant) -> Vec<HeldJob> {
+        if self.completed_ids.contains(&workflow_id) {
+            info!("Exporting a late job of {} without slack", workflow_id);
+            return vec![job];
+        }
+        if !self.jobs.contains_key(&workflow_id) {
+            self.order.push_back((workflow_id, now));
+        }
+        self.jobs.entry(workflow_id).or_default().push(job);
+        self.held += 1;
+        self.evict(now)
+    }
+
+    fn remove(&mut self, workflow_id: &Uuid, now: Instant) -> Vec<HeldJob> {
+        self.order.retain(|(id, _)| id != workflow_id);
+        if self.completed_ids.insert(*workflow_id) {
+            self.completed.push_back(*workflow_id);
+            if self.completed.len() > COMPLETED_CAPACITY {
+                if let Some(forgotten) = self.completed.pop_front() {
+                    self.completed_ids.remove(&forgotten);
+                }
+            }
+        }
+        let mut jobs = self.jobs.remove(workflow_id).unwrap_or_default();
+        self.held -= jobs.len();
+        jobs.extend(self.evict(now));
+        jobs
+    }
+
+    /// Gives up on the oldest workflows while too many jobs are held, or
+    /// they are held for too long.
+    fn evict(&mut self, now: Instant) -> Vec<HeldJob> {
+        let mut evicted = vec![];
+        while let Some(&(oldest, since)) = self.order.front() {
+            if self.held <= self.capacity && now.duration_since(since) < self.max_age {
+                break;
+            }
+            info!("Giving up on the critical path of {}", oldest);
+            self.order.pop_front();
+            let jobs = self.jobs.remove(&oldest).unwrap_or_default();
+            self.held -= jobs.len();
+            evicted.extend(jobs);
+        }
+        evicted
+    }
+}
+
+/// The slack of each job: how much longer it could have run without delaying
+/// the workflow, if every job started as soon as the jobs it required had
+/// finished. Jobs on the critical path have none. Jobs that didn't run take
+/// no time, and jobs in a dependency cycle are left out.
+pub fn analyze(jobs: &[WorkflowJob]) -> HashMap<Uuid, Duration> {
+    let duration = |job: &WorkflowJob| match (job.started_at, job.stopped_at) {
+        (Some(started_at), Some(stopped_at)) => (stopped_at - started_at).max(Duration::zero()),
+        _ => Duration::zero(),
+    };
+    let index: HashMap<Uuid, usize> = jobs.iter().enumerate().map(|(i, j)| (j.id, i)).collect();
+    let requires: Vec<Vec<usize>> = jobs
+        .iter()
+        .map(|job| {
+            job.dependencies
+                .iter()
+                .filter_map(|id| index.get(id).copied())
+                .collect()
+        })
+        .collect();
+    let mut required_by = vec![vec![]; jobs.len()];
+    for (job, dependencies) in requires.iter().enumerate() {
+        for &dependency in dependencies {
+            required_by[dependency].push(job);
+        }
+    }
+
+    // Kahn's algorithm, so that every job comes after the jobs it required
+    let mut waiting: Vec<usize> = requires.iter().map(Vec::len).collect();
+    let mut order: Vec<usize> = (0..jobs.len()).filter(|&job| waiting[job] == 0).collect();
+    let mut next = 0;
+    while next < order.len() {
+        for &successor in &required_by[order[next]] {
+            waiting[successor] -= 1;
+            if waiting[successor] == 0 {
+                order.push(successor);
+            }
+        }
+        next += 1;
+    }
+
+    let mut earliest_finish = vec![Duration::zero(); jobs.len()];
+    for &job in &order {
+        let ready = requires[job]
+            .iter()
+            .map(|&dependency| earliest_finish[dependency])
+            .max()
+            .unwrap_or_else(Duration::zero);
+        earliest_finish[job] = ready + duration(&jobs[job]);
+    }
+    let length = order
+        .iter()
+        .map(|&job| earliest_finish[job])
+        .max()
+        .unwrap_or_else(Duration::zero);
+    let mut latest_finish = vec![length; jobs.len()];
+    for &job in order.iter().rev() {
+        if let Some(latest) = required_by[job]
+            .iter()
+            .map(|&successor| latest_finish[successor] - duration(&jobs[successor]))
+            .min()
+        {
+            latest_finish[job] = latest;
+        }
+    }
+    order
+        .into_iter()
+        .map(|job| (jobs[job].id, latest_finish[job] - earliest_finish[job]))
+        .collect()
+}
+
+#[cfg(test)]
+mod analyze_tests {
+    use chrono::Duration;
+
+    use super::analyze;
+    use crate::api::WorkflowJob;
+
+    fn jobs() -> Vec<WorkflowJob> {
+        let page: serde_json::Value =
+            serde_json::from_str(include_str!("../testdata/api/workflow-jobs.json")).unwrap();
+        serde_json::from_value(page["items"].clone()).unwrap()
+    }
+
+    #[test]
+    fn test_critical_path() {
+        let jobs = jobs();
+        let slack = analyze(&jobs);
+        let slack_of = |name: &str| slack[&jobs.iter().find(|j| j.name == name).unwrap().id];
+        assert_eq!(slack_of("checkout"), Duration::zero());
+        assert_eq!(slack_of("rust/fmt"), Duration::zero());
+        assert_eq!(slack_of("rust/lint-test-build"), Duration::zero());
+        assert_eq!(slack_of("rust/audit"), Duration::milliseconds(41_232));
+        assert_eq!(slack_of("approve"), Duration::milliseconds(41_232));
+    }
+
+    #[test]
+    fn test_cycle() {
+        let mut jobs = jobs();
+        let lint = jobs[3].id;
+        jobs[0].dependencies.push(lint);
+        // every other job requires checkout, directly or not
+        assert!(analyze(&jobs).is_empty());
+    }
+}
+
+#[cfg(test)]
+mod pending_tests {
+    use std::time::{Duration, Instant};
+
+    use uuid::Uuid;
+
+    use super::{CriticalPath, HeldJob, DEFAULT_MAX_AGE};
+    use crate::{api::JobDetails, payload::WebhookPayload};
+
+    fn job() -> HeldJob {
+        (WebhookPayload::Unknown, JobDetails::default())
+    }
+
+    #[test]
+    fn test_release() {
+        let critical_path = CriticalPath::new(3, DEFAULT_MAX_AGE);
+        let workflow = Uuid::new_v4();
+        assert!(critical_path.hold(workflow, job()).is_empty());
+        assert!(critical_path.hold(workflow, job()).is_empty());
+        assert_eq!(critical_path.release(&workflow).len(), 2);
+        assert!(critical_path.release(&workflow).is_empty());
+    }
+
+    #[test]
+    fn test_late_job() {
+        let critical_path = CriticalPath::default();
+        let workflow = Uuid::new_v4();
+        assert!(critical_path.release(&workflow).is_empty());
+        // the workflow completed, so there's nothing to wait for
+        assert_eq!(critical_path.hold(workflow, job()).len(), 1);
+        assert!(critical_path.drain().is_empty());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let critical_path = CriticalPath::new(2, DEFAULT_MAX_AGE);
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        critical_path.hold(first, job());
+        critical_path.hold(first, job());
+        assert_eq!(critical_path.hold(second, job()).len(), 2);
+        assert!(critical_path.release(&first).is_empty());
+        assert_eq!(critical_path.release(&second).len(), 1);
+    }
+
+    #[test]
+    fn test_max_age() {
+        let critical_path = CriticalPath::new(10, Duration::from_secs(60));
+        let mut pending = critical_path.pending.lock().unwrap();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        assert!(pending.insert(first, job(), now).is_empty());
+        assert!(pending
+            .insert(second, job(), now + Duration::from_secs(30))
+            .is_empty());
+        let evicted = pending.insert(second, job(), now + Duration::from_secs(61));
+        assert_eq!(evicted.len(), 1);
+        let later = now + Duration::from_secs(62);
+        assert_eq!(pending.remove(&second, later).len(), 2);
+    }
+
+    #[test]
+    fn test_drain() {
+        let critical_path = CriticalPath::default();
+        critical_path.hold(Uuid::new_v4(), job());
+        critical_path.hold(Uuid::new_v4(), job());
+        assert_eq!(critical_path.drain().len(), 2);
+        assert!(critical_path.drain().is_empty());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let critical_path = CriticalPath::new(0, DEFAULT_MAX_AGE);
+        assert_eq!(critical_path.hold(Uuid::new_v4(), job()).len(), 1);
+    }
+}
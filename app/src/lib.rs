@@ -1,17 +1,37 @@
 use arrayref::array_ref;
-use http::HeaderMap;
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use critical_path::CriticalPath;
+use http::{HeaderMap, StatusCode};
 use opentelemetry::{
     sdk::trace::Tracer,
     trace::{SpanId, TraceFlags, TraceId},
 };
-use signatures::{parse_signature_header, verify_signature};
+use replay::{Duplicate, ReplayGuard};
+use semconv::AttributeMode;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
-use crate::payload::WebhookPayload;
+use crate::{
+    api::JobDetails,
+    payload::{from_json, WebhookPayload},
+};
 
+pub mod api;
+pub mod critical_path;
+pub mod metrics;
+pub mod middleware;
 pub mod payload;
+pub mod replay;
+pub mod semconv;
 pub mod signatures;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 #[derive(Error, Debug)]
 pub enum HookError {
@@ -19,40 +39,257 @@ pub enum HookError {
     SignatureVerification,
     #[error("signature header not found")]
     HeaderMissing,
-    #[error("deserialization failed")]
-    DeserializationFailed(#[from] serde_json::Error),
+    #[error("signature is not valid hex")]
+    MalformedSignature(#[from] hex::FromHexError),
+    #[error("no secret configured for the webhook")]
+    UnknownWebhook,
+    #[error("event happened outside the accepted time window")]
+    Stale,
+    #[error("event is being exported by another delivery")]
+    InFlight,
+    #[error("invalid payload at `{}`: {}", .0.path(), .0.inner())]
+    DeserializationFailed(#[from] serde_path_to_error::Error<serde_json::Error>),
+    #[error("unsupported content type {0:?}, expected application/json")]
+    UnsupportedMediaType(String),
+    #[error("payload is larger than {0} bytes")]
+    PayloadTooLarge(usize),
     #[error("unknown hook error")]
     Unknown,
 }
 
+impl HookError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            HookError::SignatureVerification
+            | HookError::HeaderMissing
+            | HookError::MalformedSignature(_)
+            | HookError::UnknownWebhook => StatusCode::UNAUTHORIZED,
+            HookError::Stale | HookError::DeserializationFailed(_) => StatusCode::BAD_REQUEST,
+            HookError::InFlight => StatusCode::CONFLICT,
+            HookError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            HookError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            HookError::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// A stable identifier of the error, for the response body.
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            HookError::SignatureVerification => "invalid_signature",
+            HookError::HeaderMissing => "missing_signature",
+            HookError::MalformedSignature(_) => "malformed_signature",
+            HookError::UnknownWebhook => "unknown_webhook",
+            HookError::Stale => "stale_event",
+            HookError::InFlight => "event_in_flight",
+            HookError::DeserializationFailed(_) => "invalid_payload",
+            HookError::UnsupportedMediaType(_) => "unsupported_media_type",
+            HookError::PayloadTooLarge(_) => "payload_too_large",
+            HookError::Unknown => "internal_error",
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        let (path, line, column) = match self {
+            HookError::DeserializationFailed(error) => (
+                Some(error.path().to_string()),
+                Some(error.inner().line()),
+                Some(error.inner().column()),
+            ),
+            _ => (None, None, None),
+        };
+        ErrorBody {
+            error: self.error_code(),
+            message: self.to_string(),
+            path,
+            line,
+            column,
+        }
+    }
+}
+
+impl IntoResponse for HookError {
+    fn into_response(self) -> Response {
+        let status = self.status_code();
+        if status.is_server_error() {
+            error!("Error processing request: {}", self);
+        } else {
+            warn!("Rejected request: {}", self);
+        }
+        (status, Json(self.body())).into_response()
+    }
+}
+
+/// The JSON body of an error response.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct ErrorBody {
+    pub error: &'static str,
+    pub message: String,
+    /// Where in the payload deserialization failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
 pub fn header_value_from_map(headers: &HeaderMap) -> Option<&str> {
     headers
         .get("circleci-signature")
         .and_then(|header| header.to_str().ok())
 }
 
-pub async fn handle_hook(
-    header_value: Option<&str>,
-    key: Option<String>,
-    body: &[u8],
-    tracer: &Tracer,
-) -> Result<&'static str, HookError> {
-    if let Some(key) = key {
-        if let Some(signature_hex) = header_value.and_then(parse_signature_header) {
-            if !verify_signature(body, key.as_bytes(), signature_hex) {
-                return Err(HookError::SignatureVerification);
+/// Accepts `application/json` and other JSON media types like
+/// `application/vnd.api+json`, with or without parameters.
+pub fn check_content_type(headers: &HeaderMap) -> Result<(), HookError> {
+    let content_type = headers
+        .get(http::header::CONTENT_TYPE)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        .unwrap_or_default();
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if media_type == "application/json"
+        || (media_type.starts_with("application/") && media_type.ends_with("+json"))
+    {
+        Ok(())
+    } else {
+        Err(HookError::UnsupportedMediaType(content_type))
+    }
+}
+
+/// Everything `handle_hook` needs besides the payload.
+#[derive(Debug)]
+pub struct Hook {
+    tracer: Tracer,
+    replay: ReplayGuard,
+    attributes: AttributeMode,
+    api: Option<api::Client>,
+    critical_path: Option<CriticalPath>,
+}
+
+impl Hook {
+    pub fn new(tracer: Tracer) -> Hook {
+        Hook {
+            tracer,
+            replay: ReplayGuard::default(),
+            attributes: AttributeMode::default(),
+            api: None,
+            critical_path: None,
+        }
+    }
+
+    pub fn with_replay(mut self, replay: ReplayGuard) -> Self {
+        self.replay = replay;
+        self
+    }
+
+    pub fn with_attributes(mut self, attributes: AttributeMode) -> Self {
+        self.attributes = attributes;
+        self
+    }
+
+    /// Adds what the CircleCI API knows about completed jobs to their spans.
+    pub fn with_api(mut self, api: api::Client) -> Self {
+        self.api = Some(api);
+        self
+    }
+
+    /// Holds back the spans of completed jobs until their workflow completes,
+    /// to mark the jobs on its critical path. Needs [`Hook::with_api`].
+    pub fn with_critical_path(mut self, critical_path: CriticalPath) -> Self {
+        self.critical_path = Some(critical_path);
+        self
+    }
+
+    /// Exports the job spans still held back, unmarked, before shutting down.
+    pub fn flush(&self) {
+        if let Some(critical_path) = &self.critical_path {
+            for (job, details) in critical_path.drain() {
+                job.build_span(&self.tracer, self.attributes, &details);
             }
-        } else {
-            return Err(HookError::HeaderMissing);
         }
     }
+}
+
+/// Translates a webhook payload into spans. The payload must have been
+/// verified already, see [`middleware::SignatureLayer`].
+pub async fn handle_hook(hook: &Hook, body: &[u8]) -> Result<&'static str, HookError> {
+    let payload = WebhookPayload::from_slice(body)?;
+    if let WebhookPayload::Unknown = payload {
+        let event_type = from_json::<UnknownEvent>(body)?.event_type;
+        info!("Ignoring unknown event type {:?}", event_type);
+        metrics::unknown_event(&event_type);
+        return Ok("Ignored unknown event");
+    }
 
-    serde_json::from_slice::<WebhookPayload>(body)
-        .map(|payload| {
-            payload.build_span(tracer);
-            "Success!"
-        })
-        .map_err(HookError::DeserializationFailed)
+    let mut claim = None;
+    if let Some((id, happened_at)) = payload.event() {
+        if !hook.replay.is_fresh(happened_at, Utc::now()) {
+            warn!("Rejecting event {} that happened at {}", id, happened_at);
+            return Err(HookError::Stale);
+        }
+        match hook.replay.claim(id) {
+            Ok(claimed) => claim = Some(claimed),
+            Err(Duplicate::Seen) => {
+                info!("Ignoring duplicate event {}", id);
+                metrics::duplicate_event();
+                return Ok("Ignored duplicate event");
+            }
+            Err(Duplicate::InFlight) => return Err(HookError::InFlight),
+        }
+    }
+
+    let details = match (&payload, &hook.api) {
+        (WebhookPayload::JobCompleted(event), Some(api)) => api.job_details(event).await,
+        _ => JobDetails::default(),
+    };
+    export(hook, payload, details).await;
+    if let Some(claim) = claim {
+        claim.commit();
+    }
+    Ok("Success!")
+}
+
+async fn export(hook: &Hook, payload: WebhookPayload, details: JobDetails) {
+    let (Some(critical_path), Some(api)) = (&hook.critical_path, &hook.api) else {
+        payload.build_span(&hook.tracer, hook.attributes, &details);
+        return;
+    };
+    match &payload {
+        WebhookPayload::JobCompleted(event) => {
+            for (job, details) in critical_path.hold(event.workflow.id, (payload, details)) {
+                job.build_span(&hook.tracer, hook.attributes, &details);
+            }
+        }
+        WebhookPayload::WorkflowCompleted(event) => {
+            // the held jobs are only released once nothing is awaited anymore,
+            // so that they stay held if the request is given up on
+            let slack = api
+                .completed_workflow_jobs(&event.workflow.id)
+                .await
+                .map(|workflow_jobs| critical_path::analyze(&workflow_jobs))
+                .unwrap_or_default();
+            let jobs = critical_path.release(&event.workflow.id);
+            for (job, mut details) in jobs {
+                if let WebhookPayload::JobCompleted(event) = &job {
+                    details.slack = slack.get(&event.job.id).copied();
+                }
+                job.build_span(&hook.tracer, hook.attributes, &details);
+            }
+            payload.build_span(&hook.tracer, hook.attributes, &details);
+        }
+        _ => payload.build_span(&hook.tracer, hook.attributes, &details),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct UnknownEvent {
+    #[serde(rename = "type")]
+    event_type: String,
 }
 
 pub fn translate_traceparent(workflow_id: Uuid, job_id: Uuid) -> String {
@@ -71,6 +308,73 @@ pub fn translate_traceparent(workflow_id: Uuid, job_id: Uuid) -> String {
     format!("export TRACEPARENT={}", header_value)
 }
 
+#[cfg(test)]
+mod content_type_tests {
+    use http::{header::CONTENT_TYPE, HeaderMap, HeaderValue};
+
+    use super::check_content_type;
+
+    fn headers(content_type: Option<&'static str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(content_type) = content_type {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_json() {
+        assert!(check_content_type(&headers(Some("application/json"))).is_ok());
+        assert!(check_content_type(&headers(Some("Application/JSON; charset=utf-8"))).is_ok());
+        assert!(check_content_type(&headers(Some("application/vnd.api+json"))).is_ok());
+    }
+
+    #[test]
+    fn test_not_json() {
+        assert!(check_content_type(&headers(Some("text/plain"))).is_err());
+        assert!(check_content_type(&headers(Some("application/x-www-form-urlencoded"))).is_err());
+        assert!(check_content_type(&headers(None)).is_err());
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use http::StatusCode;
+    use serde_json::json;
+
+    use super::HookError;
+    use crate::payload::WebhookPayload;
+
+    #[test]
+    fn test_invalid_payload() {
+        let body = b"{\n  \"type\": \"ping\",\n  \"id\": \"not-a-uuid\"\n}";
+        let error = HookError::from(WebhookPayload::from_slice(body).unwrap_err());
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+        let body = serde_json::to_value(error.body()).unwrap();
+        assert_eq!(body["error"], "invalid_payload");
+        assert_eq!(body["path"], "id");
+        assert_eq!(body["line"], 3);
+    }
+
+    #[test]
+    fn test_malformed_json() {
+        let error = HookError::from(WebhookPayload::from_slice(b"{\"type\": ").unwrap_err());
+        assert_eq!(error.error_code(), "invalid_payload");
+        assert_eq!(error.body().line, Some(1));
+    }
+
+    #[test]
+    fn test_signature_errors() {
+        for error in [HookError::HeaderMissing, HookError::SignatureVerification] {
+            assert_eq!(error.status_code(), StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(
+            serde_json::to_value(HookError::HeaderMissing.body()).unwrap(),
+            json!({"error": "missing_signature", "message": "signature header not found"})
+        );
+    }
+}
+
 #[cfg(test)]
 mod tests_traceparent {
     use uuid::Uuid;
@@ -0,0 +1,58 @@
+use std::sync::OnceLock;
+
+use opentelemetry::{global, metrics::Counter, Context, KeyValue};
+
+/// The instruments of this crate. They are created on first use, which is
+/// after `main` installed the global meter provider.
+struct Instruments {
+    unknown_events: Counter<u64>,
+    signature_matches: Counter<u64>,
+    duplicate_events: Counter<u64>,
+}
+
+fn instruments() -> &'static Instruments {
+    static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+    INSTRUMENTS.get_or_init(|| {
+        let meter = global::meter("circleci-hook");
+        Instruments {
+            unknown_events: meter
+                .u64_counter("circleci.hook.unknown_events")
+                .with_description("Webhook events with a type this server does not know about")
+                .init(),
+            signature_matches: meter
+                .u64_counter("circleci.hook.signature_matches")
+                .with_description("Webhook signatures matching one of the configured secrets")
+                .init(),
+            duplicate_events: meter
+                .u64_counter("circleci.hook.duplicate_events")
+                .with_description("Webhook events that were received before and not exported again")
+                .init(),
+        }
+    })
+}
+
+pub fn unknown_event(event_type: &str) {
+    instruments().unknown_events.add(
+        &Context::current(),
+        1,
+        &[KeyValue::new("circleci.event.type", event_type.to_string())],
+    );
+}
+
+/// Counts which secret signed the payloads, to tell when an old one can be retired.
+pub fn signature_matched(secret_name: &str, expired: bool) {
+    instruments().signature_matches.add(
+        &Context::current(),
+        1,
+        &[
+            KeyValue::new("circleci.hook.secret.name", secret_name.to_string()),
+            KeyValue::new("circleci.hook.secret.expired", expired),
+        ],
+    );
+}
+
+pub fn duplicate_event() {
+    instruments()
+        .duplicate_events
+        .add(&Context::current(), 1, &[]);
+}
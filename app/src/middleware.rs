@@ -0,0 +1,395 @@
+//! Tower layers for routes receiving CircleCI webhooks: one that only lets
+//! JSON bodies of a limited size through, and one that verifies the
+//! `circleci-signature` header against the raw request body.
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use http::{header::CONTENT_LENGTH, HeaderMap, Request};
+use http_body::{LengthLimitError, Limited};
+use serde::Deserialize;
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::warn;
+
+use crate::{
+    check_content_type, header_value_from_map,
+    signatures::{parse_signature_header, verify_signature, SecretStore},
+    HookError,
+};
+
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Reads the whole body, unless it is larger than `limit` bytes.
+pub async fn read_body(headers: &HeaderMap, body: Body, limit: usize) -> Result<Bytes, HookError> {
+    let content_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > limit) {
+        return Err(HookError::PayloadTooLarge(limit));
+    }
+    hyper::body::to_bytes(Limited::new(body, limit))
+        .await
+        .map_err(|error| {
+            if error.is::<LengthLimitError>() {
+                HookError::PayloadTooLarge(limit)
+            } else {
+                warn!("Failed to read the request body: {}", error);
+                HookError::Unknown
+            }
+        })
+}
+
+/// Checks the signature of a payload against the secrets of its webhook.
+pub fn verify_request(
+    secrets: &SecretStore,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<(), HookError> {
+    let secrets = if secrets.is_keyed() {
+        // the payload is not verified yet, it only picks the secrets to verify it with
+        let origin = serde_json::from_slice::<PayloadOrigin>(body).unwrap_or_default();
+        secrets.secrets_for(
+            origin.webhook.as_ref().map(|webhook| webhook.id.as_str()),
+            origin.project.as_ref().map(|project| project.slug.as_str()),
+        )
+    } else {
+        secrets.secrets_for(None, None)
+    }
+    .ok_or(HookError::UnknownWebhook)?;
+
+    if secrets.is_empty() {
+        return Ok(());
+    }
+    let signature_hex = header_value_from_map(headers)
+        .and_then(parse_signature_header)
+        .ok_or(HookError::HeaderMissing)?;
+    match verify_signature(body, secrets, signature_hex, Utc::now())? {
+        Some(_) => Ok(()),
+        None => Err(HookError::SignatureVerification),
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct PayloadOrigin {
+    webhook: Option<WebhookId>,
+    project: Option<ProjectSlug>,
+}
+
+#[derive(Deserialize)]
+struct WebhookId {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct ProjectSlug {
+    slug: String,
+}
+
+/// Rejects requests that are not JSON before reading their body, and bodies
+/// larger than `max_body_size` bytes. The body is buffered and passed on, so
+/// that the inner services can read it in one piece.
+#[derive(Clone, Debug)]
+pub struct JsonBodyLayer {
+    max_body_size: usize,
+}
+
+impl JsonBodyLayer {
+    pub fn new(max_body_size: usize) -> JsonBodyLayer {
+        JsonBodyLayer { max_body_size }
+    }
+}
+
+impl Default for JsonBodyLayer {
+    fn default() -> Self {
+        JsonBodyLayer::new(DEFAULT_MAX_BODY_SIZE)
+    }
+}
+
+impl<S> Layer<S> for JsonBodyLayer {
+    type Service = JsonBodyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        JsonBodyService {
+            inner,
+            max_body_size: self.max_body_size,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct JsonBodyService<S> {
+    inner: S,
+    max_body_size: usize,
+}
+
+impl<S> Service<Request<Body>> for JsonBodyService<S>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // the clone may not be ready, so keep the service polled by `poll_ready`
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let max_body_size = self.max_body_size;
+        Box::pin(async move {
+            let (parts, body) = request.into_parts();
+            let buffered = async {
+                check_content_type(&parts.headers)?;
+                read_body(&parts.headers, body, max_body_size).await
+            };
+            match buffered.await {
+                Ok(body) => {
+                    inner
+                        .call(Request::from_parts(parts, Body::from(body)))
+                        .await
+                }
+                Err(error) => Ok(error.into_response()),
+            }
+        })
+    }
+}
+
+/// Rejects requests without a valid signature before they reach the inner
+/// service. The body is buffered to verify it, and passed on unchanged.
+#[derive(Clone, Debug)]
+pub struct SignatureLayer {
+    secrets: Arc<SecretStore>,
+    max_body_size: usize,
+}
+
+impl SignatureLayer {
+    pub fn new(secrets: Arc<SecretStore>) -> SignatureLayer {
+        SignatureLayer {
+            secrets,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+
+    /// Rejects bodies larger than this many bytes instead of buffering them.
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+}
+
+impl<S> Layer<S> for SignatureLayer {
+    type Service = SignatureService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SignatureService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SignatureService<S> {
+    inner: S,
+    layer: SignatureLayer,
+}
+
+impl<S> Service<Request<Body>> for SignatureService<S>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // the clone may not be ready, so keep the service polled by `poll_ready`
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+        Box::pin(async move {
+            let (parts, body) = request.into_parts();
+            let verified = async {
+                let body = read_body(&parts.headers, body, layer.max_body_size).await?;
+                verify_request(&layer.secrets, &parts.headers, &body)?;
+                Ok::<_, HookError>(body)
+            };
+            match verified.await {
+                Ok(body) => {
+                    inner
+                        .call(Request::from_parts(parts, Body::from(body)))
+                        .await
+                }
+                Err(error) => Ok(error.into_response()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod signature_layer_tests {
+    use std::sync::Arc;
+
+    use axum::{body::Body, routing::post, Router};
+    use http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::SignatureLayer;
+    use crate::signatures::{SecretStore, UnknownWebhookPolicy, WebhookSecret};
+
+    fn app() -> Router {
+        let secrets = SecretStore::new(
+            vec![WebhookSecret::new("current", "hunter123", None)],
+            UnknownWebhookPolicy::Default,
+        );
+        Router::new().route(
+            "/",
+            post(|body: String| async move { body })
+                .layer(SignatureLayer::new(Arc::new(secrets)).with_max_body_size(64)),
+        )
+    }
+
+    fn request(signature: Option<&str>, body: &'static str) -> Request<Body> {
+        let mut request = Request::post("/");
+        if let Some(signature) = signature {
+            request = request.header("circleci-signature", signature);
+        }
+        request.body(Body::from(body)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_valid_signature() {
+        let response = app()
+            .oneshot(request(
+                Some("v1=9be2242094a9a8c00c64306f382a7f9d691de910b4a266f67bd314ef18ac49fa"),
+                "an-important-request-payload",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"an-important-request-payload");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_signature() {
+        let response = app()
+            .oneshot(request(
+                Some("v1=9be2242094a9a8c00c64306f382a7f9d691de910b4a266f67bd314ef18ac49fa"),
+                "a-tampered-request-payload",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_missing_signature() {
+        let response = app()
+            .oneshot(request(None, "an-important-request-payload"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_body_too_large() {
+        let response = app()
+            .oneshot(request(
+                Some("v1=00"),
+                "an-important-request-payload, but much longer than the limit of the layer",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
+
+#[cfg(test)]
+mod json_body_layer_tests {
+    use std::sync::Arc;
+
+    use axum::{body::Body, routing::post, Router};
+    use http::{header::CONTENT_TYPE, Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::{JsonBodyLayer, SignatureLayer};
+    use crate::signatures::{SecretStore, UnknownWebhookPolicy, WebhookSecret};
+
+    /// As the server composes them, the JSON check goes first.
+    fn app() -> Router {
+        let secrets = SecretStore::new(
+            vec![WebhookSecret::new("current", "hunter123", None)],
+            UnknownWebhookPolicy::Default,
+        );
+        Router::new().route(
+            "/",
+            post(|body: String| async move { body })
+                .layer(SignatureLayer::new(Arc::new(secrets)))
+                .layer(JsonBodyLayer::new(64)),
+        )
+    }
+
+    fn request(content_type: &'static str, body: &'static str) -> Request<Body> {
+        Request::post("/")
+            .header(CONTENT_TYPE, content_type)
+            .header(
+                "circleci-signature",
+                "v1=9be2242094a9a8c00c64306f382a7f9d691de910b4a266f67bd314ef18ac49fa",
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_json() {
+        let response = app()
+            .oneshot(request("application/json", "an-important-request-payload"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_unsigned_form() {
+        let request = Request::post("/")
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from("an-important-request-payload"))
+            .unwrap();
+        let response = app().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_body_too_large() {
+        let response = app()
+            .oneshot(request(
+                "application/json",
+                "an-important-request-payload, but much longer than the limit of the layer",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
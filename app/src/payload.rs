@@ -3,139 +3,761 @@ use chrono::{DateTime, FixedOffset};
 use opentelemetry::{
     sdk::trace::Tracer,
     trace::{
-        SpanBuilder, SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
-        Tracer as TracerTrait,
+        Link, Span, SpanBuilder, SpanContext, SpanId, Status, TraceContextExt, TraceFlags, TraceId,
+        TraceState, Tracer as TracerTrait,
     },
-    Context, Key, KeyValue, StringValue, Value,
+    Array, Context, Key, KeyValue, StringValue, Value,
 };
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tracing::{debug, info};
 use uuid::Uuid;
 
+use crate::{
+    api::{Action, Artifact, JobDetails, Step, TestResult, WorkflowJob},
+    semconv::{self, AttributeMode},
+};
+
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum WebhookPayload {
     #[serde(rename = "ping")]
-    PingEvent {
-        id: Uuid,
-        happened_at: DateTime<FixedOffset>,
-        webhook: Webhook,
-    },
+    PingEvent(PingEvent),
     #[serde(rename = "workflow-completed")]
-    WorkflowCompleted {
-        id: Uuid,
-        happened_at: String,
-        organization: Organization,
-        project: Project,
-        pipeline: Pipeline,
-        webhook: Webhook,
-        workflow: Workflow,
-    },
+    WorkflowCompleted(WorkflowCompleted),
     #[serde(rename = "job-completed")]
-    JobCompleted {
-        id: Uuid,
-        happened_at: String,
-        organization: Organization,
-        project: Project,
-        pipeline: Pipeline,
-        webhook: Webhook,
-        workflow: Workflow,
-        job: Job,
-    },
+    JobCompleted(JobCompleted),
+    /// Any event type this version doesn't know about yet. These are accepted
+    /// and ignored, so that new event types don't get the webhook disabled.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PingEvent {
+    pub id: Uuid,
+    pub happened_at: DateTime<FixedOffset>,
+    pub webhook: Webhook,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WorkflowCompleted {
+    pub id: Uuid,
+    pub happened_at: DateTime<FixedOffset>,
+    pub organization: Organization,
+    pub project: Project,
+    pub pipeline: Pipeline,
+    pub webhook: Webhook,
+    pub workflow: Workflow,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JobCompleted {
+    pub id: Uuid,
+    pub happened_at: DateTime<FixedOffset>,
+    pub organization: Organization,
+    pub project: Project,
+    pub pipeline: Pipeline,
+    pub webhook: Webhook,
+    pub workflow: Workflow,
+    pub job: Job,
+}
+
+/// Deserializes JSON, keeping track of where in the document it fails.
+pub(crate) fn from_json<T: DeserializeOwned>(
+    body: &[u8],
+) -> Result<T, serde_path_to_error::Error<serde_json::Error>> {
+    serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_slice(body))
+}
+
+#[derive(Deserialize)]
+struct EventType {
+    #[serde(rename = "type")]
+    event_type: String,
 }
 
 impl WebhookPayload {
-    pub fn build_span(&self, tracer: &Tracer) {
+    /// Like `serde_json::from_slice`, but the event is deserialized directly
+    /// instead of through serde's buffering of tagged enums, so errors point
+    /// to the offending field.
+    pub fn from_slice(
+        body: &[u8],
+    ) -> Result<WebhookPayload, serde_path_to_error::Error<serde_json::Error>> {
+        Ok(match from_json::<EventType>(body)?.event_type.as_str() {
+            "ping" => WebhookPayload::PingEvent(from_json(body)?),
+            "workflow-completed" => WebhookPayload::WorkflowCompleted(from_json(body)?),
+            "job-completed" => WebhookPayload::JobCompleted(from_json(body)?),
+            _ => WebhookPayload::Unknown,
+        })
+    }
+
+    /// The id of the event and when it happened, for events this version knows.
+    pub fn event(&self) -> Option<(Uuid, DateTime<FixedOffset>)> {
+        match self {
+            WebhookPayload::PingEvent(PingEvent {
+                id, happened_at, ..
+            })
+            | WebhookPayload::WorkflowCompleted(WorkflowCompleted {
+                id, happened_at, ..
+            })
+            | WebhookPayload::JobCompleted(JobCompleted {
+                id, happened_at, ..
+            }) => Some((*id, *happened_at)),
+            WebhookPayload::Unknown => None,
+        }
+    }
+
+    /// Spans are ended explicitly: when dropped, the SDK replaces an end time
+    /// equal to the start time with the current time.
+    pub fn build_span(&self, tracer: &Tracer, mode: AttributeMode, details: &JobDetails) {
         match self {
-            WebhookPayload::PingEvent {
+            WebhookPayload::PingEvent(PingEvent {
                 id,
                 happened_at,
                 webhook,
-            } => {
+            }) => {
                 info!("Processing PingEvent");
-                tracer.build(
-                    SpanBuilder::from_name("ping")
-                        .with_trace_id(TraceId::from_bytes(*id.as_bytes()))
-                        .with_start_time(*happened_at)
-                        .with_end_time(*happened_at)
-                        .with_attributes([webhook.to_kv()].concat()),
-                );
+                tracer
+                    .build(
+                        SpanBuilder::from_name("ping")
+                            .with_trace_id(TraceId::from_bytes(*id.as_bytes()))
+                            .with_span_id(SpanId::from_bytes(*array_ref!(id.as_bytes(), 0, 8)))
+                            .with_start_time(*happened_at)
+                            .with_attributes([webhook.to_kv()].concat()),
+                    )
+                    .end_with_timestamp((*happened_at).into());
             }
 
-            WebhookPayload::JobCompleted {
+            WebhookPayload::JobCompleted(JobCompleted {
                 id: _,
-                happened_at: _,
+                happened_at,
                 organization,
                 project,
                 pipeline,
                 webhook,
                 workflow,
                 job,
-            } => {
-                if let Some(stopped_at) = job.stopped_at {
-                    debug!("pipeline: {:#?}", pipeline);
-                    info!("Processing JobCompleted");
-                    tracer.build_with_context(
+            }) => {
+                debug!("pipeline: {:#?}", pipeline);
+                info!("Processing JobCompleted");
+                let (stopped_at, stopped_kv) =
+                    or_fallback("stopped_at", job.stopped_at, *happened_at);
+                let (started_at, started_kv) =
+                    or_fallback("started_at", job.started_at, stopped_at);
+                tracer
+                    .build_with_context(
                         SpanBuilder::from_name(format!("job: {}", job.name))
                             .with_span_id(job.span_id())
-                            .with_start_time(job.started_at)
-                            .with_end_time(stopped_at)
+                            .with_links(dependency_links(workflow, &details.dependencies))
+                            .with_start_time(started_at)
+                            .with_status(job.span_status())
                             .with_attributes(
                                 [
                                     vec![KeyValue {
                                         key: Key::new("circleci.kind"),
                                         value: Value::String(StringValue::from("job")),
                                     }],
-                                    organization.to_kv(),
-                                    project.to_kv(),
-                                    pipeline.to_kv(),
+                                    started_kv,
+                                    stopped_kv,
+                                    organization.to_kv(mode),
+                                    project.to_kv(mode),
+                                    pipeline.to_kv(mode),
                                     webhook.to_kv(),
-                                    workflow.to_kv(),
-                                    job.to_kv(),
+                                    workflow.to_kv(mode),
+                                    job.to_kv(workflow, mode),
+                                    details
+                                        .tests
+                                        .as_deref()
+                                        .map(tests_to_kv)
+                                        .unwrap_or_default(),
+                                    artifacts_to_kv(details),
+                                    details.slack.map(slack_to_kv).unwrap_or_default(),
                                 ]
                                 .concat(),
                             ),
                         &workflow.context(),
-                    );
+                    )
+                    .end_with_timestamp(stopped_at.into());
+                let job_context = job.context(workflow);
+                for step in &details.steps {
+                    build_step_spans(tracer, job, &job_context, step);
+                }
+                let tests = details.tests.iter().flatten().enumerate();
+                for (index, test) in tests.filter(|(_, test)| details.test_spans.includes(test)) {
+                    build_test_span(tracer, job, &job_context, started_at, index, test);
                 }
             }
 
-            WebhookPayload::WorkflowCompleted {
+            WebhookPayload::WorkflowCompleted(WorkflowCompleted {
                 id: _,
-                happened_at: _,
+                happened_at,
                 organization,
                 project,
                 pipeline,
                 webhook,
                 workflow,
-            } => {
-                if let Some(stopped_at) = workflow.stopped_at {
-                    info!("Processing WorkflowCompleted");
-                    tracer.build(
+            }) => {
+                info!("Processing WorkflowCompleted");
+                let (stopped_at, stopped_kv) =
+                    or_fallback("stopped_at", workflow.stopped_at, *happened_at);
+                tracer
+                    .build(
                         SpanBuilder::from_name(format!("workflow: {}", workflow.name))
                             .with_trace_id(workflow.trace_id())
                             .with_span_id(workflow.span_id())
                             .with_start_time(workflow.created_at)
-                            .with_end_time(stopped_at)
+                            .with_status(workflow.span_status())
                             .with_attributes(
                                 [
                                     vec![KeyValue {
                                         key: Key::new("circleci.kind"),
                                         value: Value::String(StringValue::from("workflow")),
                                     }],
-                                    organization.to_kv(),
-                                    project.to_kv(),
-                                    pipeline.to_kv(),
+                                    stopped_kv,
+                                    organization.to_kv(mode),
+                                    project.to_kv(mode),
+                                    pipeline.to_kv(mode),
                                     webhook.to_kv(),
-                                    workflow.to_kv(),
+                                    workflow.to_kv(mode),
                                 ]
                                 .concat(),
                             ),
-                    );
+                    )
+                    .end_with_timestamp(stopped_at.into());
+            }
+
+            WebhookPayload::Unknown => {
+                debug!("Skipping unknown event");
+            }
+        }
+    }
+}
+
+/// A span id for something within the span of `id`, the same for every
+/// delivery of the event.
+fn derived_span_id(id: &Uuid, path: &str) -> SpanId {
+    let hash = Sha256::new()
+        .chain_update(id.as_bytes())
+        .chain_update(path)
+        .finalize();
+    SpanId::from_bytes(*array_ref!(hash.as_slice(), 0, 8))
+}
+
+/// One span per step, and below it one span per parallel node if the job ran
+/// on more than one. Nodes that didn't start the step are left out.
+fn build_step_spans(tracer: &Tracer, job: &Job, job_context: &Context, step: &Step) {
+    let actions: Vec<&Action> = step
+        .actions
+        .iter()
+        .filter(|action| action.start_time.is_some())
+        .collect();
+    let (Some(first), Some(start_time), Some(end_time)) = (
+        actions.first(),
+        actions.iter().filter_map(|action| action.start_time).min(),
+        actions.iter().map(|action| action.end()).max(),
+    ) else {
+        return;
+    };
+    let failed = actions.iter().find(|action| action.status != "success");
+    let mut attributes = vec![
+        KeyValue {
+            key: Key::new("circleci.kind"),
+            value: Value::String(StringValue::from("step")),
+        },
+        KeyValue {
+            key: Key::new("circleci.step.name"),
+            value: Value::String(StringValue::from(step.name.clone())),
+        },
+        KeyValue {
+            key: Key::new("circleci.step.number"),
+            value: Value::I64(first.step),
+        },
+    ];
+    if let [action] = actions.as_slice() {
+        attributes.extend(action.to_kv());
+    }
+    let span_id = derived_span_id(&job.id, &format!("step/{}", first.step));
+    tracer
+        .build_with_context(
+            SpanBuilder::from_name(format!("step: {}", step.name))
+                .with_span_id(span_id)
+                .with_start_time(start_time)
+                .with_status(span_status(
+                    "step",
+                    failed.map_or(&first.status, |action| &action.status),
+                ))
+                .with_attributes(attributes),
+            job_context,
+        )
+        .end_with_timestamp(end_time.into());
+    if actions.len() < 2 {
+        return;
+    }
+
+    let step_context = job_context.with_remote_span_context(SpanContext::new(
+        job_context.span().span_context().trace_id(),
+        span_id,
+        TraceFlags::SAMPLED,
+        false,
+        TraceState::default(),
+    ));
+    for action in actions {
+        tracer
+            .build_with_context(
+                SpanBuilder::from_name(format!("step: {} [node {}]", step.name, action.index))
+                    .with_span_id(derived_span_id(
+                        &job.id,
+                        &format!("step/{}/{}", action.step, action.index),
+                    ))
+                    .with_start_time(action.start_time.unwrap())
+                    .with_status(span_status("step", &action.status))
+                    .with_attributes(
+                        [
+                            vec![
+                                KeyValue {
+                                    key: Key::new("circleci.kind"),
+                                    value: Value::String(StringValue::from("step")),
+                                },
+                                KeyValue {
+                                    key: Key::new("circleci.step.name"),
+                                    value: Value::String(StringValue::from(step.name.clone())),
+                                },
+                                KeyValue {
+                                    key: Key::new("circleci.step.number"),
+                                    value: Value::I64(action.step),
+                                },
+                            ],
+                            action.to_kv(),
+                        ]
+                        .concat(),
+                    ),
+                &step_context,
+            )
+            .end_with_timestamp(action.end().into());
+    }
+}
+
+/// The longest message kept of a failed test.
+const MAX_TEST_MESSAGE: usize = 1024;
+
+fn tests_to_kv(tests: &[TestResult]) -> Vec<KeyValue> {
+    let count = |filter: fn(&TestResult) -> bool| tests.iter().filter(|t| filter(t)).count();
+    let mut result = vec![
+        KeyValue {
+            key: Key::new("circleci.tests.total"),
+            value: Value::I64(tests.len() as i64),
+        },
+        KeyValue {
+            key: Key::new("circleci.tests.failed"),
+            value: Value::I64(count(TestResult::failed) as i64),
+        },
+        KeyValue {
+            key: Key::new("circleci.tests.skipped"),
+            value: Value::I64(count(TestResult::skipped) as i64),
+        },
+    ];
+    if let Some(slowest) = tests
+        .iter()
+        .max_by(|a, b| a.run_time.total_cmp(&b.run_time))
+    {
+        result.extend([
+            KeyValue {
+                key: Key::new("circleci.tests.slowest.name"),
+                value: Value::String(StringValue::from(slowest.name.clone())),
+            },
+            KeyValue {
+                key: Key::new("circleci.tests.slowest.run_time"),
+                value: Value::F64(slowest.run_time),
+            },
+        ]);
+    }
+    result
+}
+
+/// The paths and urls of the artifacts as two lists of the same order.
+fn artifacts_to_kv(details: &JobDetails) -> Vec<KeyValue> {
+    let Some(count) = details.artifact_count else {
+        return vec![];
+    };
+    let list = |field: fn(&Artifact) -> &String| {
+        Value::Array(Array::String(
+            details
+                .artifacts
+                .iter()
+                .map(|artifact| StringValue::from(field(artifact).clone()))
+                .collect(),
+        ))
+    };
+    let mut result = vec![KeyValue {
+        key: Key::new("circleci.artifacts.count"),
+        value: Value::I64(count as i64),
+    }];
+    if !details.artifacts.is_empty() {
+        result.extend([
+            KeyValue {
+                key: Key::new("circleci.artifacts.path"),
+                value: list(|artifact| &artifact.path),
+            },
+            KeyValue {
+                key: Key::new("circleci.artifacts.url"),
+                value: list(|artifact| &artifact.url),
+            },
+        ]);
+    }
+    result
+}
+
+fn slack_to_kv(slack: chrono::Duration) -> Vec<KeyValue> {
+    vec![
+        KeyValue {
+            key: Key::new("circleci.job.critical_path"),
+            value: Value::Bool(slack.is_zero()),
+        },
+        KeyValue {
+            key: Key::new("circleci.job.slack"),
+            value: Value::F64(slack.num_milliseconds() as f64 / 1000.0),
+        },
+    ]
+}
+
+/// Links to the spans of the jobs in `workflow` that a job required. Jobs
+/// that didn't run have no span, but are linked all the same.
+fn dependency_links(workflow: &Workflow, dependencies: &[WorkflowJob]) -> Vec<Link> {
+    dependencies
+        .iter()
+        .map(|dependency| {
+            Link::new(
+                SpanContext::new(
+                    workflow.trace_id(),
+                    SpanId::from_bytes(*array_ref!(dependency.id.as_bytes(), 0, 8)),
+                    TraceFlags::SAMPLED,
+                    false,
+                    TraceState::default(),
+                ),
+                vec![
+                    KeyValue {
+                        key: Key::new("circleci.job.name"),
+                        value: Value::String(StringValue::from(dependency.name.clone())),
+                    },
+                    KeyValue {
+                        key: Key::new("circleci.job.status"),
+                        value: Value::String(StringValue::from(dependency.status.clone())),
+                    },
+                ],
+            )
+        })
+        .collect()
+}
+
+/// The API only has the duration of a test, so its span starts with the job.
+fn build_test_span(
+    tracer: &Tracer,
+    job: &Job,
+    job_context: &Context,
+    started_at: DateTime<FixedOffset>,
+    index: usize,
+    test: &TestResult,
+) {
+    let mut attributes = vec![
+        KeyValue {
+            key: Key::new("circleci.kind"),
+            value: Value::String(StringValue::from("test")),
+        },
+        KeyValue {
+            key: Key::new("test.case.name"),
+            value: Value::String(StringValue::from(test.name.clone())),
+        },
+        KeyValue {
+            key: Key::new("circleci.test.result"),
+            value: Value::String(StringValue::from(test.result.clone())),
+        },
+        KeyValue {
+            key: Key::new("circleci.test.run_time"),
+            value: Value::F64(test.run_time),
+        },
+    ];
+    if !test.classname.is_empty() {
+        attributes.push(KeyValue {
+            key: Key::new("test.suite.name"),
+            value: Value::String(StringValue::from(test.classname.clone())),
+        });
+    }
+    if !test.skipped() {
+        attributes.push(KeyValue {
+            key: Key::new("test.case.result.status"),
+            value: Value::String(StringValue::from(if test.failed() {
+                "fail"
+            } else {
+                "pass"
+            })),
+        });
+    }
+    if let Some(file) = &test.file {
+        attributes.push(KeyValue {
+            key: Key::new("circleci.test.file"),
+            value: Value::String(StringValue::from(file.clone())),
+        });
+    }
+    if let Some(message) = test.message.as_ref().filter(|_| test.failed()) {
+        attributes.push(KeyValue {
+            key: Key::new("circleci.test.message"),
+            value: Value::String(StringValue::from(
+                message.chars().take(MAX_TEST_MESSAGE).collect::<String>(),
+            )),
+        });
+    }
+    let status = if test.failed() {
+        Status::error(format!("test {}", test.result))
+    } else {
+        Status::Ok
+    };
+    let end_time = started_at + chrono::Duration::microseconds((test.run_time * 1e6) as i64);
+    tracer
+        .build_with_context(
+            SpanBuilder::from_name(format!("test: {}", test.name))
+                .with_span_id(derived_span_id(&job.id, &format!("test/{}", index)))
+                .with_start_time(started_at)
+                .with_status(status)
+                .with_attributes(attributes),
+            job_context,
+        )
+        .end_with_timestamp(end_time.into());
+}
+
+impl Action {
+    /// Steps that never finished end when they started.
+    fn end(&self) -> DateTime<FixedOffset> {
+        self.end_time.or(self.start_time).unwrap_or_default()
+    }
+
+    fn to_kv(&self) -> Vec<KeyValue> {
+        let mut result = vec![
+            KeyValue {
+                key: Key::new("circleci.step.node"),
+                value: Value::I64(self.index),
+            },
+            KeyValue {
+                key: Key::new("circleci.step.status"),
+                value: Value::String(StringValue::from(self.status.clone())),
+            },
+        ];
+        if let Some(exit_code) = self.exit_code {
+            result.push(KeyValue {
+                key: Key::new("circleci.step.exit_code"),
+                value: Value::I64(exit_code),
+            });
+        }
+        if self.background {
+            result.push(KeyValue {
+                key: Key::new("circleci.step.background"),
+                value: Value::Bool(true),
+            });
+        }
+        result
+    }
+}
+
+/// Returns `timestamp`, or `fallback` together with an attribute marking
+/// that `name` was missing from the payload.
+fn or_fallback(
+    name: &str,
+    timestamp: Option<DateTime<FixedOffset>>,
+    fallback: DateTime<FixedOffset>,
+) -> (DateTime<FixedOffset>, Vec<KeyValue>) {
+    match timestamp {
+        Some(timestamp) => (timestamp, vec![]),
+        None => (
+            fallback,
+            vec![KeyValue {
+                key: Key::new(format!("circleci.{}.fallback", name)),
+                value: Value::Bool(true),
+            }],
+        ),
+    }
+}
+
+#[cfg(test)]
+mod fallback_tests {
+    use chrono::DateTime;
+    use opentelemetry::{Key, Value};
+
+    use super::or_fallback;
+
+    #[test]
+    fn test_present() {
+        let stopped_at = DateTime::parse_from_rfc3339("2022-08-27T20:26:31.289Z").unwrap();
+        let happened_at = DateTime::parse_from_rfc3339("2022-08-27T20:26:31.353978Z").unwrap();
+        let (timestamp, kv) = or_fallback("stopped_at", Some(stopped_at), happened_at);
+        assert_eq!(timestamp, stopped_at);
+        assert!(kv.is_empty());
+    }
+
+    #[test]
+    fn test_missing() {
+        let happened_at = DateTime::parse_from_rfc3339("2022-08-27T20:26:31.353978Z").unwrap();
+        let (timestamp, kv) = or_fallback("stopped_at", None, happened_at);
+        assert_eq!(timestamp, happened_at);
+        assert_eq!(kv[0].key, Key::new("circleci.stopped_at.fallback"));
+        assert_eq!(kv[0].value, Value::Bool(true));
+    }
+}
+
+#[cfg(test)]
+mod payload_tests {
+    use super::{JobCompleted, WebhookPayload};
+
+    #[test]
+    fn test_unknown_type() {
+        let payload: WebhookPayload = serde_json::from_str(
+            r#"{
+                "type": "pipeline-started",
+                "id": "00f3055f-d25c-4641-bdcd-33e19f3b5d7d",
+                "happened_at": "2022-08-27T20:16:36.531665Z"
+            }"#,
+        )
+        .unwrap();
+        assert!(matches!(payload, WebhookPayload::Unknown));
+    }
+
+    #[test]
+    fn test_job_without_timestamps() {
+        let payload: WebhookPayload = serde_json::from_str(
+            r#"{
+                "type": "job-completed",
+                "id": "ba0c8055-1f10-326e-8cf2-d7a4f5432d23",
+                "happened_at": "2022-08-27T20:26:31.353978Z",
+                "job": {
+                    "id": "20e45d7e-e4a7-4aa3-8f92-fd6d9d01da75",
+                    "name": "rust/lint-test-build",
+                    "number": 10,
+                    "started_at": null,
+                    "status": "canceled",
+                    "stopped_at": null
+                },
+                "organization": {
+                    "id": "b689dafb-ccea-4a88-8d20-f380ef2b439c",
+                    "name": "DavidS"
+                },
+                "pipeline": {
+                    "created_at": "2022-08-27T20:25:40.570Z",
+                    "id": "2bed20e7-711a-45cf-b7e8-017a0575a26c",
+                    "number": 10
+                },
+                "project": {
+                    "id": "1fbc30b3-cdb4-4874-a42e-abb81ffd0364",
+                    "name": "circleci-hook",
+                    "slug": "github/DavidS/circleci-hook"
+                },
+                "webhook": {
+                    "id": "d4ab06bc-eb79-463d-8aa4-47d066382d3b",
+                    "name": "ngrok test"
+                },
+                "workflow": {
+                    "created_at": "2022-08-27T20:25:40.675Z",
+                    "id": "410c427b-40a8-4bb4-9d42-5561f5bce5ba",
+                    "name": "production",
+                    "url": "https://app.circleci.com/pipelines/github/DavidS/circleci-hook/10/workflows/410c427b-40a8-4bb4-9d42-5561f5bce5ba"
                 }
+            }"#,
+        )
+        .unwrap();
+        match payload {
+            WebhookPayload::JobCompleted(JobCompleted { job, .. }) => {
+                assert_eq!(job.started_at, None);
+                assert_eq!(job.stopped_at, None);
             }
+            _ => panic!("expected a JobCompleted event, got {:?}", payload),
+        }
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let payload: WebhookPayload = serde_json::from_str(
+            r#"{
+                "type": "ping",
+                "id": "00f3055f-d25c-4641-bdcd-33e19f3b5d7d",
+                "happened_at": "2022-08-27T20:16:36.531665Z",
+                "webhook": {
+                    "id": "d4ab06bc-eb79-463d-8aa4-47d066382d3b",
+                    "name": "ngrok test",
+                    "url": "https://example.com/"
+                },
+                "new_field": {"nested": true}
+            }"#,
+        )
+        .unwrap();
+        assert!(matches!(payload, WebhookPayload::PingEvent(_)));
+    }
+
+    #[test]
+    fn test_from_slice_error_path() {
+        let error = WebhookPayload::from_slice(
+            br#"{
+                "type": "ping",
+                "id": "00f3055f-d25c-4641-bdcd-33e19f3b5d7d",
+                "happened_at": "2022-08-27T20:16:36.531665Z",
+                "webhook": {"id": "not-a-uuid", "name": "ngrok test"}
+            }"#,
+        )
+        .unwrap_err();
+        assert_eq!(error.path().to_string(), "webhook.id");
+        assert_eq!(error.inner().line(), 5);
+    }
+
+    #[test]
+    fn test_from_slice_unknown_type() {
+        let payload = WebhookPayload::from_slice(br#"{"type": "pipeline-started"}"#).unwrap();
+        assert!(matches!(payload, WebhookPayload::Unknown));
+    }
+}
+
+/// Maps a CircleCI job or workflow status onto the OpenTelemetry span status.
+fn span_status(kind: &str, status: &str) -> Status {
+    match status {
+        "success" => Status::Ok,
+        "failed"
+        | "error"
+        | "failing"
+        | "canceled"
+        | "unauthorized"
+        | "timedout"
+        | "infrastructure_fail" => Status::error(format!("{} {}", kind, status)),
+        _ => Status::Unset,
+    }
+}
+
+#[cfg(test)]
+mod status_tests {
+    use opentelemetry::trace::Status;
+
+    use super::span_status;
+
+    #[test]
+    fn test_success() {
+        assert_eq!(span_status("job", "success"), Status::Ok);
+    }
+
+    #[test]
+    fn test_errors() {
+        for status in [
+            "failed",
+            "error",
+            "canceled",
+            "unauthorized",
+            "timedout",
+            "infrastructure_fail",
+        ] {
+            assert_eq!(
+                span_status("job", status),
+                Status::error(format!("job {}", status))
+            );
         }
     }
+
+    #[test]
+    fn test_unknown() {
+        assert_eq!(span_status("workflow", "on_hold"), Status::Unset);
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -145,17 +767,24 @@ pub struct Organization {
 }
 
 impl Organization {
-    fn to_kv(&self) -> Vec<KeyValue> {
-        vec![
-            KeyValue {
-                key: Key::new("circleci.organization.id"),
-                value: Value::String(format!("{}", self.id.urn()).into()),
-            },
-            KeyValue {
+    fn to_kv(&self, mode: AttributeMode) -> Vec<KeyValue> {
+        let mut result = vec![KeyValue {
+            key: Key::new("circleci.organization.id"),
+            value: Value::String(format!("{}", self.id.urn()).into()),
+        }];
+        if mode.legacy() {
+            result.push(KeyValue {
                 key: Key::new("circleci.organization.name"),
                 value: Value::String(StringValue::from(self.name.clone())),
-            },
-        ]
+            });
+        }
+        if mode.semconv() {
+            result.push(KeyValue {
+                key: Key::new(semconv::VCS_OWNER_NAME),
+                value: Value::String(StringValue::from(self.name.clone())),
+            });
+        }
+        result
     }
 }
 
@@ -167,21 +796,30 @@ pub struct Project {
 }
 
 impl Project {
-    fn to_kv(&self) -> Vec<KeyValue> {
-        vec![
+    fn to_kv(&self, mode: AttributeMode) -> Vec<KeyValue> {
+        let mut result = vec![
             KeyValue {
                 key: Key::new("circleci.project.id"),
                 value: Value::String(format!("{}", self.id.urn()).into()),
             },
-            KeyValue {
-                key: Key::new("circleci.project.name"),
-                value: Value::String(StringValue::from(self.name.clone())),
-            },
             KeyValue {
                 key: Key::new("circleci.project.slug"),
                 value: Value::String(StringValue::from(self.slug.clone())),
             },
-        ]
+        ];
+        if mode.legacy() {
+            result.push(KeyValue {
+                key: Key::new("circleci.project.name"),
+                value: Value::String(StringValue::from(self.name.clone())),
+            });
+        }
+        if mode.semconv() {
+            result.push(KeyValue {
+                key: Key::new(semconv::VCS_REPOSITORY_NAME),
+                value: Value::String(StringValue::from(self.name.clone())),
+            });
+        }
+        result
     }
 }
 
@@ -190,15 +828,13 @@ pub struct Pipeline {
     pub created_at: DateTime<FixedOffset>,
     pub id: Uuid,
     pub number: i64,
-    // TODO: complete full deserialisation here
-    pub trigger: Option<serde_json::Value>,
-    // TODO: complete full deserialisation here
-    pub vcs: Option<serde_json::Value>,
+    pub trigger: Option<Trigger>,
+    pub vcs: Option<Vcs>,
 }
 
 impl Pipeline {
-    fn to_kv(&self) -> Vec<KeyValue> {
-        vec![
+    fn to_kv(&self, mode: AttributeMode) -> Vec<KeyValue> {
+        let mut result = vec![
             KeyValue {
                 key: Key::new("circleci.pipeline.id"),
                 value: Value::String(format!("{}", self.id.urn()).into()),
@@ -207,7 +843,11 @@ impl Pipeline {
                 key: Key::new("circleci.pipeline.number"),
                 value: Value::I64(self.number),
             },
-        ]
+        ];
+        if let Some(vcs) = &self.vcs {
+            result.extend(vcs.to_kv(mode));
+        }
+        result
     }
 }
 
@@ -245,6 +885,12 @@ impl Workflow {
         SpanId::from_bytes(*array_ref!(self.id.as_bytes(), 0, 8))
     }
 
+    fn span_status(&self) -> Status {
+        self.status
+            .as_deref()
+            .map_or(Status::Unset, |status| span_status("workflow", status))
+    }
+
     fn context(&self) -> Context {
         let cx = Context::current();
         cx.with_remote_span_context(SpanContext::new(
@@ -256,26 +902,51 @@ impl Workflow {
         ))
     }
 
-    fn to_kv(&self) -> Vec<KeyValue> {
-        let mut result = vec![
-            KeyValue {
-                key: Key::new("circleci.workflow.id"),
-                value: Value::String(format!("{}", self.id.urn()).into()),
-            },
-            KeyValue {
-                key: Key::new("circleci.workflow.name"),
-                value: Value::String(StringValue::from(self.name.clone())),
-            },
-            KeyValue {
-                key: Key::new("circleci.workflow.url"),
-                value: Value::String(StringValue::from(self.url.clone())),
-            },
-        ];
-        if let Some(status) = &self.status {
-            result.push(KeyValue {
-                key: Key::new("circleci.workflow.status"),
-                value: Value::String(StringValue::from(status.clone())),
-            });
+    fn to_kv(&self, mode: AttributeMode) -> Vec<KeyValue> {
+        let mut result = vec![];
+        if mode.legacy() {
+            result.extend([
+                KeyValue {
+                    key: Key::new("circleci.workflow.id"),
+                    value: Value::String(format!("{}", self.id.urn()).into()),
+                },
+                KeyValue {
+                    key: Key::new("circleci.workflow.name"),
+                    value: Value::String(StringValue::from(self.name.clone())),
+                },
+                KeyValue {
+                    key: Key::new("circleci.workflow.url"),
+                    value: Value::String(StringValue::from(self.url.clone())),
+                },
+            ]);
+            if let Some(status) = &self.status {
+                result.push(KeyValue {
+                    key: Key::new("circleci.workflow.status"),
+                    value: Value::String(StringValue::from(status.clone())),
+                });
+            }
+        }
+        if mode.semconv() {
+            result.extend([
+                KeyValue {
+                    key: Key::new(semconv::CICD_PIPELINE_NAME),
+                    value: Value::String(StringValue::from(self.name.clone())),
+                },
+                KeyValue {
+                    key: Key::new(semconv::CICD_PIPELINE_RUN_ID),
+                    value: Value::String(self.id.to_string().into()),
+                },
+                KeyValue {
+                    key: Key::new(semconv::CICD_PIPELINE_RUN_URL_FULL),
+                    value: Value::String(StringValue::from(self.url.clone())),
+                },
+            ]);
+            if let Some(result_value) = self.status.as_deref().and_then(semconv::result) {
+                result.push(KeyValue {
+                    key: Key::new(semconv::CICD_PIPELINE_RESULT),
+                    value: Value::String(StringValue::from(result_value)),
+                });
+            }
         }
         result
     }
@@ -299,7 +970,7 @@ pub struct Job {
     pub id: Uuid,
     pub name: String,
     pub number: i64,
-    pub started_at: DateTime<FixedOffset>,
+    pub started_at: Option<DateTime<FixedOffset>>,
     pub status: String,
     pub stopped_at: Option<DateTime<FixedOffset>>,
 }
@@ -309,168 +980,395 @@ impl Job {
         SpanId::from_bytes(*array_ref!(self.id.as_bytes(), 0, 8))
     }
 
+    /// The context of the job span, for the spans within it.
+    fn context(&self, workflow: &Workflow) -> Context {
+        workflow
+            .context()
+            .with_remote_span_context(SpanContext::new(
+                workflow.trace_id(),
+                self.span_id(),
+                TraceFlags::SAMPLED,
+                false,
+                TraceState::default(),
+            ))
+    }
+
+    fn span_status(&self) -> Status {
+        span_status("job", &self.status)
+    }
+
+    /// CircleCI's page of the job, within the page of its workflow.
+    fn url(&self, workflow: &Workflow) -> String {
+        format!(
+            "{}/jobs/{}",
+            workflow.url.trim_end_matches('/'),
+            self.number
+        )
+    }
+
+    fn to_kv(&self, workflow: &Workflow, mode: AttributeMode) -> Vec<KeyValue> {
+        let mut result = vec![KeyValue {
+            key: Key::new("circleci.job.number"),
+            value: Value::I64(self.number),
+        }];
+        if mode.legacy() {
+            result.extend([
+                KeyValue {
+                    key: Key::new("circleci.job.id"),
+                    value: Value::String(format!("{}", self.id.urn()).into()),
+                },
+                KeyValue {
+                    key: Key::new("circleci.job.name"),
+                    value: Value::String(StringValue::from(self.name.clone())),
+                },
+                KeyValue {
+                    key: Key::new("circleci.job.status"),
+                    value: Value::String(StringValue::from(self.status.clone())),
+                },
+            ]);
+        }
+        if mode.semconv() {
+            result.extend([
+                KeyValue {
+                    key: Key::new(semconv::CICD_PIPELINE_TASK_NAME),
+                    value: Value::String(StringValue::from(self.name.clone())),
+                },
+                KeyValue {
+                    key: Key::new(semconv::CICD_PIPELINE_TASK_RUN_ID),
+                    value: Value::String(self.id.to_string().into()),
+                },
+                KeyValue {
+                    key: Key::new(semconv::CICD_PIPELINE_TASK_RUN_URL_FULL),
+                    value: Value::String(self.url(workflow).into()),
+                },
+            ]);
+            if let Some(result_value) = semconv::result(&self.status) {
+                result.push(KeyValue {
+                    key: Key::new(semconv::CICD_PIPELINE_TASK_RUN_RESULT),
+                    value: Value::String(StringValue::from(result_value)),
+                });
+            }
+        }
+        result
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct Trigger {
+    #[serde(rename = "type")]
+    pub trigger_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<DateTime<FixedOffset>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<Actor>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct Actor {
+    pub login: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct Vcs {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit: Option<Commit>,
+    pub origin_repository_url: String,
+    pub provider_name: String,
+    pub revision: String,
+    pub target_repository_url: String,
+}
+
+impl Vcs {
+    fn to_kv(&self, mode: AttributeMode) -> Vec<KeyValue> {
+        let mut result = vec![];
+        if mode.legacy() {
+            result.extend([
+                KeyValue {
+                    key: Key::new("vcs.revision"),
+                    value: Value::String(StringValue::from(self.revision.clone())),
+                },
+                KeyValue {
+                    key: Key::new("vcs.repository.url"),
+                    value: Value::String(StringValue::from(self.target_repository_url.clone())),
+                },
+                KeyValue {
+                    key: Key::new("vcs.provider"),
+                    value: Value::String(StringValue::from(self.provider_name.clone())),
+                },
+            ]);
+            if let Some(branch) = &self.branch {
+                result.push(KeyValue {
+                    key: Key::new("vcs.branch"),
+                    value: Value::String(StringValue::from(branch.clone())),
+                });
+            }
+            if let Some(tag) = &self.tag {
+                result.push(KeyValue {
+                    key: Key::new("vcs.tag"),
+                    value: Value::String(StringValue::from(tag.clone())),
+                });
+            }
+        }
+        if mode.semconv() {
+            result.extend([
+                KeyValue {
+                    key: Key::new(semconv::VCS_REF_HEAD_REVISION),
+                    value: Value::String(StringValue::from(self.revision.clone())),
+                },
+                KeyValue {
+                    key: Key::new(semconv::VCS_REPOSITORY_URL_FULL),
+                    value: Value::String(StringValue::from(self.target_repository_url.clone())),
+                },
+                KeyValue {
+                    key: Key::new(semconv::VCS_PROVIDER_NAME),
+                    value: Value::String(StringValue::from(self.provider_name.clone())),
+                },
+            ]);
+            // a tagged pipeline has no branch
+            let head = match (&self.branch, &self.tag) {
+                (Some(branch), _) => Some((branch, "branch")),
+                (None, Some(tag)) => Some((tag, "tag")),
+                (None, None) => None,
+            };
+            if let Some((name, ref_type)) = head {
+                result.extend([
+                    KeyValue {
+                        key: Key::new(semconv::VCS_REF_HEAD_NAME),
+                        value: Value::String(StringValue::from(name.clone())),
+                    },
+                    KeyValue {
+                        key: Key::new(semconv::VCS_REF_HEAD_TYPE),
+                        value: Value::String(StringValue::from(ref_type)),
+                    },
+                ]);
+            }
+            // CircleCI builds pull requests from forks on a `pull/<number>` branch
+            if let Some(change) = self.branch.as_deref().and_then(|b| b.strip_prefix("pull/")) {
+                result.push(KeyValue {
+                    key: Key::new(semconv::VCS_CHANGE_ID),
+                    value: Value::String(StringValue::from(change.to_owned())),
+                });
+            }
+        }
+        if let Some(commit) = &self.commit {
+            result.extend(commit.to_kv());
+        }
+        result
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct Commit {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<Person>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authored_at: Option<DateTime<FixedOffset>>,
+    #[serde(default)]
+    pub body: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub committed_at: Option<DateTime<FixedOffset>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub committer: Option<Person>,
+    pub subject: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct Person {
+    pub email: String,
+    pub name: String,
+}
+
+impl Commit {
     fn to_kv(&self) -> Vec<KeyValue> {
-        vec![
-            KeyValue {
-                key: Key::new("circleci.job.id"),
-                value: Value::String(format!("{}", self.id.urn()).into()),
-            },
-            KeyValue {
-                key: Key::new("circleci.job.name"),
-                value: Value::String(StringValue::from(self.name.clone())),
-            },
-            KeyValue {
-                key: Key::new("circleci.job.number"),
-                value: Value::I64(self.number),
-            },
-            KeyValue {
-                key: Key::new("circleci.job.status"),
-                value: Value::String(StringValue::from(self.status.clone())),
+        let mut result = vec![KeyValue {
+            key: Key::new("vcs.commit.subject"),
+            value: Value::String(StringValue::from(self.subject.clone())),
+        }];
+        if let Some(author) = &self.author {
+            result.push(KeyValue {
+                key: Key::new("vcs.commit.author.name"),
+                value: Value::String(StringValue::from(author.name.clone())),
+            });
+        }
+        if let Some(committer) = &self.committer {
+            result.push(KeyValue {
+                key: Key::new("vcs.commit.committer.name"),
+                value: Value::String(StringValue::from(committer.name.clone())),
+            });
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod pipeline_tests {
+    use std::collections::HashMap;
+
+    use opentelemetry::{Key, Value};
+
+    use super::{Pipeline, Trigger, Vcs};
+    use crate::semconv::AttributeMode;
+
+    const PIPELINE: &str = r#"{
+        "created_at": "2022-08-27T20:25:40.570Z",
+        "id": "2bed20e7-711a-45cf-b7e8-017a0575a26c",
+        "number": 10,
+        "trigger": {
+            "type": "webhook"
+        },
+        "vcs": {
+            "branch": "main",
+            "commit": {
+                "author": {
+                    "email": "david@black.co.at",
+                    "name": "David Schmitt"
+                },
+                "authored_at": "2022-08-27T20:25:35Z",
+                "body": "",
+                "committed_at": "2022-08-27T20:25:35Z",
+                "committer": {
+                    "email": "david@black.co.at",
+                    "name": "David Schmitt"
+                },
+                "subject": "chore: implement basic event debugging and the PingEvent"
             },
-        ]
-    }
-}
-
-// TODO: complete full deserialisation here
-// #[derive(Deserialize, Debug)]
-// pub struct Vcs {
-//     branch: String,
-//     // TODO: complete full deserialisation here
-//     commit: serde_json::Value,
-//     origin_repository_url: String,
-//     provider_name: String,
-//     revision: String,
-//     target_repository_url: String,
-// }
-
-// Example webhook payload:
-// {
-//     "happened_at": "2022-08-27T20:16:36.531665Z",
-//     "id": "00f3055f-d25c-4641-bdcd-33e19f3b5d7d",
-//     "type": "ping",
-//     "webhook": {
-//         "id": "d4ab06bc-eb79-463d-8aa4-47d066382d3b",
-//         "name": "ngrok test"
-//     }
-// }
-
-// Example webhook payload: "workflow-completed"
-// Object {
-//     "happened_at": String("2022-08-27T20:26:31.388615Z"),
-//     "id": String("46924cd3-e825-30da-8036-b2f293194bc9"),
-//     "organization": Object {
-//         "id": String("b689dafb-ccea-4a88-8d20-f380ef2b439c"),
-//         "name": String("DavidS"),
-//     },
-//     "pipeline": Object {
-//         "created_at": String("2022-08-27T20:25:40.570Z"),
-//         "id": String("2bed20e7-711a-45cf-b7e8-017a0575a26c"),
-//         "number": Number(10),
-//         "trigger": Object {
-//             "type": String("webhook"),
-//         },
-//         "vcs": Object {
-//             "branch": String("main"),
-//             "commit": Object {
-//                 "author": Object {
-//                     "email": String("david@black.co.at"),
-//                     "name": String("David Schmitt"),
-//                 },
-//                 "authored_at": String("2022-08-27T20:25:35Z"),
-//                 "body": String(""),
-//                 "committed_at": String("2022-08-27T20:25:35Z"),
-//                 "committer": Object {
-//                     "email": String("david@black.co.at"),
-//                     "name": String("David Schmitt"),
-//                 },
-//                 "subject": String("chore: implement basic event debugging and the PingEvent"),
-//             },
-//             "origin_repository_url": String("https://github.com/DavidS/circleci-hook"),
-//             "provider_name": String("github"),
-//             "revision": String("71eb8857ea7e13f36021af32f3b7cc9304b491dd"),
-//             "target_repository_url": String("https://github.com/DavidS/circleci-hook"),
-//         },
-//     },
-//     "project": Object {
-//         "id": String("1fbc30b3-cdb4-4874-a42e-abb81ffd0364"),
-//         "name": String("circleci-hook"),
-//         "slug": String("github/DavidS/circleci-hook"),
-//     },
-//     "type": String("workflow-completed"),
-//     "webhook": Object {
-//         "id": String("d4ab06bc-eb79-463d-8aa4-47d066382d3b"),
-//         "name": String("ngrok test"),
-//     },
-//     "workflow": Object {
-//         "created_at": String("2022-08-27T20:25:40.675Z"),
-//         "id": String("410c427b-40a8-4bb4-9d42-5561f5bce5ba"),
-//         "name": String("production"),
-//         "status": String("success"),
-//         "stopped_at": String("2022-08-27T20:26:31.289Z"),
-//         "url": String("https://app.circleci.com/pipelines/github/DavidS/circleci-hook/10/workflows/410c427b-40a8-4bb4-9d42-5561f5bce5ba"),
-//     },
-// }
-
-// Example webhook payload: "job-completed"
-// Object {
-//     "happened_at": String("2022-08-27T20:26:31.353978Z"),
-//     "id": String("ba0c8055-1f10-326e-8cf2-d7a4f5432d23"),
-//     "job": Object {
-//         "id": String("20e45d7e-e4a7-4aa3-8f92-fd6d9d01da75"),
-//         "name": String("rust/lint-test-build"),
-//         "number": Number(10),
-//         "started_at": String("2022-08-27T20:25:43.007Z"),
-//         "status": String("success"),
-//         "stopped_at": String("2022-08-27T20:26:31.289Z"),
-//     },
-//     "organization": Object {
-//         "id": String("b689dafb-ccea-4a88-8d20-f380ef2b439c"),
-//         "name": String("DavidS"),
-//     },
-//     "pipeline": Object {
-//         "created_at": String("2022-08-27T20:25:40.570Z"),
-//         "id": String("2bed20e7-711a-45cf-b7e8-017a0575a26c"),
-//         "number": Number(10),
-//         "trigger": Object {
-//             "type": String("webhook"),
-//         },
-//         "vcs": Object {
-//             "branch": String("main"),
-//             "commit": Object {
-//                 "author": Object {
-//                     "email": String("david@black.co.at"),
-//                     "name": String("David Schmitt"),
-//                 },
-//                 "authored_at": String("2022-08-27T20:25:35Z"),
-//                 "body": String(""),
-//                 "committed_at": String("2022-08-27T20:25:35Z"),
-//                 "committer": Object {
-//                     "email": String("david@black.co.at"),
-//                     "name": String("David Schmitt"),
-//                 },
-//                 "subject": String("chore: implement basic event debugging and the PingEvent"),
-//             },
-//             "origin_repository_url": String("https://github.com/DavidS/circleci-hook"),
-//             "provider_name": String("github"),
-//             "revision": String("71eb8857ea7e13f36021af32f3b7cc9304b491dd"),
-//             "target_repository_url": String("https://github.com/DavidS/circleci-hook"),
-//         },
-//     },
-//     "project": Object {
-//         "id": String("1fbc30b3-cdb4-4874-a42e-abb81ffd0364"),
-//         "name": String("circleci-hook"),
-//         "slug": String("github/DavidS/circleci-hook"),
-//     },
-//     "type": String("job-completed"),
-//     "webhook": Object {
-//         "id": String("d4ab06bc-eb79-463d-8aa4-47d066382d3b"),
-//         "name": String("ngrok test"),
-//     },
-//     "workflow": Object {
-//         "created_at": String("2022-08-27T20:25:40.675Z"),
-//         "id": String("410c427b-40a8-4bb4-9d42-5561f5bce5ba"),
-//         "name": String("production"),
-//         "stopped_at": String("2022-08-27T20:26:31.289Z"),
-//         "url": String("https://app.circleci.com/pipelines/github/DavidS/circleci-hook/10/workflows/410c427b-40a8-4bb4-9d42-5561f5bce5ba"),
-//     },
-// }
+            "origin_repository_url": "https://github.com/DavidS/circleci-hook",
+            "provider_name": "github",
+            "revision": "71eb8857ea7e13f36021af32f3b7cc9304b491dd",
+            "target_repository_url": "https://github.com/DavidS/circleci-hook"
+        }
+    }"#;
+
+    #[test]
+    fn test_pipeline() {
+        let pipeline: Pipeline = serde_json::from_str(PIPELINE).unwrap();
+        assert_eq!(pipeline.number, 10);
+        assert_eq!(pipeline.trigger.unwrap().trigger_type, "webhook");
+
+        let vcs = pipeline.vcs.unwrap();
+        assert_eq!(vcs.branch.as_deref(), Some("main"));
+        assert_eq!(vcs.tag, None);
+        assert_eq!(vcs.provider_name, "github");
+        assert_eq!(vcs.revision, "71eb8857ea7e13f36021af32f3b7cc9304b491dd");
+
+        let commit = vcs.commit.unwrap();
+        assert_eq!(commit.author.unwrap().name, "David Schmitt");
+        assert_eq!(commit.committer.unwrap().email, "david@black.co.at");
+        assert_eq!(
+            commit.subject,
+            "chore: implement basic event debugging and the PingEvent"
+        );
+    }
+
+    #[test]
+    fn test_pipeline_to_kv() {
+        let pipeline: Pipeline = serde_json::from_str(PIPELINE).unwrap();
+        let kv: HashMap<Key, Value> = pipeline
+            .to_kv(AttributeMode::Legacy)
+            .into_iter()
+            .map(|kv| (kv.key, kv.value))
+            .collect();
+
+        assert_eq!(kv[&Key::new("circleci.pipeline.number")], Value::I64(10));
+        assert_eq!(kv[&Key::new("vcs.branch")], Value::from("main"));
+        assert_eq!(
+            kv[&Key::new("vcs.revision")],
+            Value::from("71eb8857ea7e13f36021af32f3b7cc9304b491dd")
+        );
+        assert_eq!(
+            kv[&Key::new("vcs.repository.url")],
+            Value::from("https://github.com/DavidS/circleci-hook")
+        );
+        assert_eq!(kv[&Key::new("vcs.provider")], Value::from("github"));
+        assert_eq!(
+            kv[&Key::new("vcs.commit.author.name")],
+            Value::from("David Schmitt")
+        );
+        assert_eq!(
+            kv[&Key::new("vcs.commit.committer.name")],
+            Value::from("David Schmitt")
+        );
+        assert_eq!(
+            kv[&Key::new("vcs.commit.subject")],
+            Value::from("chore: implement basic event debugging and the PingEvent")
+        );
+        assert!(!kv.contains_key(&Key::new("vcs.tag")));
+        assert!(!kv.contains_key(&Key::new("vcs.ref.head.name")));
+    }
+
+    #[test]
+    fn test_pipeline_to_kv_semconv() {
+        let pipeline: Pipeline = serde_json::from_str(PIPELINE).unwrap();
+        let kv: HashMap<Key, Value> = pipeline
+            .to_kv(AttributeMode::Semconv)
+            .into_iter()
+            .map(|kv| (kv.key, kv.value))
+            .collect();
+
+        assert_eq!(kv[&Key::new("circleci.pipeline.number")], Value::I64(10));
+        assert_eq!(kv[&Key::new("vcs.ref.head.name")], Value::from("main"));
+        assert_eq!(kv[&Key::new("vcs.ref.head.type")], Value::from("branch"));
+        assert_eq!(
+            kv[&Key::new("vcs.ref.head.revision")],
+            Value::from("71eb8857ea7e13f36021af32f3b7cc9304b491dd")
+        );
+        assert_eq!(kv[&Key::new("vcs.provider.name")], Value::from("github"));
+        assert_eq!(
+            kv[&Key::new("vcs.commit.subject")],
+            Value::from("chore: implement basic event debugging and the PingEvent")
+        );
+        assert!(!kv.contains_key(&Key::new("vcs.branch")));
+        assert!(!kv.contains_key(&Key::new("vcs.change.id")));
+    }
+
+    #[test]
+    fn test_vcs_roundtrip() {
+        let pipeline: serde_json::Value = serde_json::from_str(PIPELINE).unwrap();
+        let vcs: Vcs = serde_json::from_value(pipeline["vcs"].clone()).unwrap();
+        let roundtrip: Vcs = serde_json::from_value(serde_json::to_value(&vcs).unwrap()).unwrap();
+        assert_eq!(vcs, roundtrip);
+    }
+
+    #[test]
+    fn test_tag_without_branch() {
+        let vcs: Vcs = serde_json::from_str(
+            r#"{
+                "tag": "v1.0.0",
+                "origin_repository_url": "https://github.com/DavidS/circleci-hook",
+                "provider_name": "github",
+                "revision": "71eb8857ea7e13f36021af32f3b7cc9304b491dd",
+                "target_repository_url": "https://github.com/DavidS/circleci-hook"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(vcs.branch, None);
+        assert_eq!(vcs.tag.as_deref(), Some("v1.0.0"));
+        assert_eq!(vcs.commit, None);
+    }
+
+    #[test]
+    fn test_trigger_roundtrip() {
+        let trigger: Trigger = serde_json::from_str(
+            r#"{
+                "type": "api",
+                "received_at": "2022-08-27T20:25:40.570Z",
+                "actor": {
+                    "login": "DavidS",
+                    "avatar_url": "https://avatars.githubusercontent.com/u/175097"
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(trigger.trigger_type, "api");
+        assert_eq!(trigger.actor.as_ref().unwrap().login, "DavidS");
+        let roundtrip: Trigger =
+            serde_json::from_value(serde_json::to_value(&trigger).unwrap()).unwrap();
+        assert_eq!(trigger, roundtrip);
+    }
+}
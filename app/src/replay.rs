@@ -0,0 +1,298 @@
+//! Protection against replayed webhook deliveries. Events too far from the
+//! current time are rejected, and the ids of recent events are remembered so
+//! that duplicates, including CircleCI's own retries, are not exported twice.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use tracing::warn;
+use uuid::Uuid;
+
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+#[derive(Debug)]
+pub struct ReplayGuard {
+    tolerance: Option<Duration>,
+    seen: Mutex<SeenEvents>,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        ReplayGuard::new(None, DEFAULT_CAPACITY)
+    }
+}
+
+impl ReplayGuard {
+    /// Rejects events that happened more than `tolerance` before or after now,
+    /// and remembers the ids of the last `capacity` events.
+    pub fn new(tolerance: Option<Duration>, capacity: usize) -> ReplayGuard {
+        ReplayGuard {
+            tolerance,
+            seen: Mutex::new(SeenEvents {
+                capacity,
+                order: VecDeque::with_capacity(capacity),
+                ids: HashSet::with_capacity(capacity),
+                in_flight: HashSet::new(),
+                persistence: None,
+            }),
+        }
+    }
+
+    /// Keeps the seen ids in a file, so they survive a restart. Ids already in
+    /// the file are loaded.
+    pub fn with_persistence(self, path: impl Into<PathBuf>) -> io::Result<ReplayGuard> {
+        let path = path.into();
+        {
+            let mut seen = self.seen.lock().unwrap();
+            if path.exists() {
+                for line in BufReader::new(File::open(&path)?).lines() {
+                    if let Ok(id) = Uuid::parse_str(line?.trim()) {
+                        seen.remember(id);
+                    }
+                }
+            }
+            let persistence = Persistence::open(path, &seen.order)?;
+            seen.persistence = Some(persistence);
+        }
+        Ok(self)
+    }
+
+    pub fn is_fresh(&self, happened_at: DateTime<FixedOffset>, now: DateTime<Utc>) -> bool {
+        let age = now - happened_at.with_timezone(&Utc);
+        self.tolerance
+            .is_none_or(|tolerance| age.num_milliseconds().abs() <= tolerance.num_milliseconds())
+    }
+
+    /// Claims the event id for one delivery until its claim is committed or
+    /// dropped, unless it was recorded before or is claimed already. The id
+    /// is only recorded once committed, so that a redelivery of an event whose
+    /// request timed out is not ignored.
+    pub fn claim(&self, id: Uuid) -> Result<Claim<'_>, Duplicate> {
+        let mut seen = self.seen.lock().unwrap();
+        if seen.ids.contains(&id) {
+            return Err(Duplicate::Seen);
+        }
+        if seen.capacity > 0 && !seen.in_flight.insert(id) {
+            return Err(Duplicate::InFlight);
+        }
+        Ok(Claim { guard: self, id })
+    }
+
+    /// Records the event id, returning `false` if it was seen before.
+    pub fn first_seen(&self, id: Uuid) -> bool {
+        self.seen.lock().unwrap().insert(id)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Duplicate {
+    /// The event was recorded before.
+    Seen,
+    /// Another delivery of the event holds a claim on it.
+    InFlight,
+}
+
+/// The claim of a delivery on an event id, released when dropped.
+#[must_use]
+#[derive(Debug)]
+pub struct Claim<'a> {
+    guard: &'a ReplayGuard,
+    id: Uuid,
+}
+
+impl Claim<'_> {
+    /// Records the event id, once the event was exported.
+    pub fn commit(self) {
+        self.guard.seen.lock().unwrap().insert(self.id);
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.guard.seen.lock().unwrap().in_flight.remove(&self.id);
+    }
+}
+
+#[derive(Debug)]
+struct SeenEvents {
+    capacity: usize,
+    order: VecDeque<Uuid>,
+    ids: HashSet<Uuid>,
+    /// The claimed ids, see [`ReplayGuard::claim`].
+    in_flight: HashSet<Uuid>,
+    persistence: Option<Persistence>,
+}
+
+impl SeenEvents {
+    fn insert(&mut self, id: Uuid) -> bool {
+        if self.ids.contains(&id) {
+            return false;
+        }
+        self.remember(id);
+        if let Some(persistence) = &mut self.persistence {
+            if let Err(error) = persistence.append(id, &self.order) {
+                warn!("Failed to persist seen event ids: {}", error);
+            }
+        }
+        true
+    }
+
+    fn remember(&mut self, id: Uuid) {
+        if self.capacity == 0 || !self.ids.insert(id) {
+            return;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.ids.remove(&evicted);
+            }
+        }
+    }
+}
+
+/// An append-only file of ids, compacted to the remembered ones once it has
+/// grown to twice the capacity.
+#[derive(Debug)]
+struct Persistence {
+    path: PathBuf,
+    file: File,
+    lines: usize,
+}
+
+impl Persistence {
+    fn open(path: PathBuf, ids: &VecDeque<Uuid>) -> io::Result<Persistence> {
+        let file = Persistence::compact(&path, ids)?;
+        Ok(Persistence {
+            path,
+            file,
+            lines: ids.len(),
+        })
+    }
+
+    fn append(&mut self, id: Uuid, ids: &VecDeque<Uuid>) -> io::Result<()> {
+        writeln!(self.file, "{}", id)?;
+        self.lines += 1;
+        if self.lines > 2 * ids.len().max(1) {
+            self.file = Persistence::compact(&self.path, ids)?;
+            self.lines = ids.len();
+        }
+        Ok(())
+    }
+
+    /// Where the file is rewritten before it replaces `path`. The suffix is
+    /// appended rather than replacing the extension, which may be `.tmp`.
+    fn temporary(path: &Path) -> PathBuf {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".compact");
+        temporary.into()
+    }
+
+    /// Rewrites the file with just `ids` and opens it for appending.
+    fn compact(path: &Path, ids: &VecDeque<Uuid>) -> io::Result<File> {
+        let temporary = Persistence::temporary(path);
+        let mut file = File::create(&temporary)?;
+        for id in ids {
+            writeln!(file, "{}", id)?;
+        }
+        file.sync_all()?;
+        fs::rename(&temporary, path)?;
+        OpenOptions::new().append(true).open(path)
+    }
+}
+
+#[cfg(test)]
+mod replay_tests {
+    use std::{env, path::Path};
+
+    use chrono::{Duration, Utc};
+    use uuid::Uuid;
+
+    use super::{Duplicate, Persistence, ReplayGuard};
+
+    #[test]
+    fn test_duplicates() {
+        let guard = ReplayGuard::default();
+        let id = Uuid::new_v4();
+        assert!(guard.first_seen(id));
+        assert!(!guard.first_seen(id));
+        assert!(guard.first_seen(Uuid::new_v4()));
+    }
+
+    #[test]
+    fn test_claim() {
+        let guard = ReplayGuard::default();
+        let id = Uuid::new_v4();
+        let claim = guard.claim(id).unwrap();
+        assert_eq!(guard.claim(id).unwrap_err(), Duplicate::InFlight);
+        drop(claim);
+        // given up on, so a redelivery can claim it again
+        let claim = guard.claim(id).unwrap();
+        claim.commit();
+        assert_eq!(guard.claim(id).unwrap_err(), Duplicate::Seen);
+        assert!(!guard.first_seen(id));
+    }
+
+    #[test]
+    fn test_claim_disabled() {
+        let guard = ReplayGuard::new(None, 0);
+        let id = Uuid::new_v4();
+        let _claim = guard.claim(id).unwrap();
+        guard.claim(id).unwrap().commit();
+        assert!(guard.claim(id).is_ok());
+    }
+
+    #[test]
+    fn test_eviction() {
+        let guard = ReplayGuard::new(None, 2);
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for id in &ids {
+            assert!(guard.first_seen(*id));
+        }
+        assert!(guard.first_seen(ids[0]));
+        assert!(!guard.first_seen(ids[2]));
+    }
+
+    #[test]
+    fn test_tolerance() {
+        let guard = ReplayGuard::new(Some(Duration::minutes(5)), 0);
+        let now = Utc::now();
+        assert!(guard.is_fresh((now - Duration::minutes(4)).into(), now));
+        assert!(guard.is_fresh((now + Duration::minutes(4)).into(), now));
+        assert!(!guard.is_fresh((now - Duration::minutes(6)).into(), now));
+        assert!(ReplayGuard::default().is_fresh((now - Duration::days(6)).into(), now));
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = env::temp_dir().join(format!("seen-events-{}", Uuid::new_v4()));
+        let ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        {
+            let guard = ReplayGuard::new(None, 3).with_persistence(&path).unwrap();
+            for id in &ids {
+                assert!(guard.first_seen(*id));
+            }
+        }
+        let guard = ReplayGuard::new(None, 3).with_persistence(&path).unwrap();
+        assert!(guard.first_seen(ids[1]));
+        assert!(!guard.first_seen(ids[4]));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_temporary_path() {
+        assert_eq!(
+            Persistence::temporary(Path::new("/var/lib/hook/seen.tmp")),
+            Path::new("/var/lib/hook/seen.tmp.compact")
+        );
+        assert_eq!(
+            Persistence::temporary(Path::new("seen")),
+            Path::new("seen.compact")
+        );
+    }
+}
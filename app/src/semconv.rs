@@ -0,0 +1,76 @@
+//! Attribute names from the OpenTelemetry CI/CD and VCS semantic conventions.
+//! A CircleCI workflow maps onto a pipeline run, and a job onto a task run.
+
+use serde::Deserialize;
+
+pub const CICD_PIPELINE_NAME: &str = "cicd.pipeline.name";
+pub const CICD_PIPELINE_RESULT: &str = "cicd.pipeline.result";
+pub const CICD_PIPELINE_RUN_ID: &str = "cicd.pipeline.run.id";
+pub const CICD_PIPELINE_RUN_URL_FULL: &str = "cicd.pipeline.run.url.full";
+pub const CICD_PIPELINE_TASK_NAME: &str = "cicd.pipeline.task.name";
+pub const CICD_PIPELINE_TASK_RUN_ID: &str = "cicd.pipeline.task.run.id";
+pub const CICD_PIPELINE_TASK_RUN_RESULT: &str = "cicd.pipeline.task.run.result";
+pub const CICD_PIPELINE_TASK_RUN_URL_FULL: &str = "cicd.pipeline.task.run.url.full";
+pub const VCS_CHANGE_ID: &str = "vcs.change.id";
+pub const VCS_OWNER_NAME: &str = "vcs.owner.name";
+pub const VCS_PROVIDER_NAME: &str = "vcs.provider.name";
+pub const VCS_REF_HEAD_NAME: &str = "vcs.ref.head.name";
+pub const VCS_REF_HEAD_REVISION: &str = "vcs.ref.head.revision";
+pub const VCS_REF_HEAD_TYPE: &str = "vcs.ref.head.type";
+pub const VCS_REPOSITORY_NAME: &str = "vcs.repository.name";
+pub const VCS_REPOSITORY_URL_FULL: &str = "vcs.repository.url.full";
+
+/// Which attribute names the spans carry. The `circleci.*` and `vcs.*` names
+/// this server used before the semantic conventions existed are `Legacy`.
+/// Attributes without a counterpart in the conventions are always emitted.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttributeMode {
+    Legacy,
+    Semconv,
+    #[default]
+    Both,
+}
+
+impl AttributeMode {
+    pub fn legacy(self) -> bool {
+        self != AttributeMode::Semconv
+    }
+
+    pub fn semconv(self) -> bool {
+        self != AttributeMode::Legacy
+    }
+}
+
+/// Maps a CircleCI job or workflow status onto `cicd.pipeline.result`.
+pub fn result(status: &str) -> Option<&'static str> {
+    match status {
+        "success" => Some("success"),
+        "failed" | "failing" => Some("failure"),
+        "error" | "infrastructure_fail" | "unauthorized" => Some("error"),
+        "timedout" => Some("timeout"),
+        "canceled" => Some("cancellation"),
+        "not_run" => Some("skip"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod result_tests {
+    use super::{result, AttributeMode};
+
+    #[test]
+    fn test_result() {
+        assert_eq!(result("success"), Some("success"));
+        assert_eq!(result("failed"), Some("failure"));
+        assert_eq!(result("canceled"), Some("cancellation"));
+        assert_eq!(result("on_hold"), None);
+    }
+
+    #[test]
+    fn test_mode() {
+        assert!(AttributeMode::Both.legacy() && AttributeMode::Both.semconv());
+        assert!(!AttributeMode::Legacy.semconv());
+        assert!(!AttributeMode::Semconv.legacy());
+    }
+}
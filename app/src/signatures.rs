@@ -1,26 +1,196 @@
+use std::{collections::HashMap, fmt};
+
+use chrono::{DateTime, Utc};
+use hex::FromHexError;
 use hmac::{digest::FixedOutput, Hmac, Mac};
+use serde::Deserialize;
 use sha2::Sha256;
 use subtle::ConstantTimeEq;
 use tracing::{debug, warn};
 
+use crate::metrics;
+
 // Create alias for HMAC-SHA256
 type HmacSha256 = Hmac<Sha256>;
 
-pub fn verify_signature(body: &[u8], key: &[u8], signature_hex: String) -> bool {
-    let signature = hex::decode(signature_hex).expect("Decoding failed");
-    debug!(
-        "VERIFYING: body={:?}, key={:?}, signature={:?}",
-        body, key, signature
-    );
-    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take key of any size");
-    mac.update(body);
-    let result = mac.finalize_fixed();
-    if result.ct_eq(&signature).into() {
-        // debug!("SUCCESS!");
-        return true;
-    }
-    warn!("FAILED signature verification: {:?}", result);
-    false
+/// A secret CircleCI may have signed a payload with. Several can be accepted
+/// at once, so the secret can be rotated without rejecting deliveries.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookSecret {
+    /// Identifies the secret in logs and metrics, never the secret itself.
+    pub name: String,
+    value: String,
+    /// After this time payloads signed with the secret are rejected.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl WebhookSecret {
+    pub fn new(
+        name: impl Into<String>,
+        value: impl Into<String>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> WebhookSecret {
+        WebhookSecret {
+            name: name.into(),
+            value: value.into(),
+            expires_at,
+        }
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl fmt::Debug for WebhookSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebhookSecret")
+            .field("name", &self.name)
+            .field("value", &"[redacted]")
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+/// What to do with payloads from a webhook or project without its own secrets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownWebhookPolicy {
+    /// Reject the payload.
+    Reject,
+    /// Verify the payload with the default secrets.
+    #[default]
+    Default,
+}
+
+/// The secrets to verify payloads with, looked up by webhook id first and by
+/// project slug second.
+#[derive(Debug, Default)]
+pub struct SecretStore {
+    default: Vec<WebhookSecret>,
+    webhooks: HashMap<String, Vec<WebhookSecret>>,
+    projects: HashMap<String, Vec<WebhookSecret>>,
+    unknown_webhooks: UnknownWebhookPolicy,
+}
+
+impl SecretStore {
+    pub fn new(default: Vec<WebhookSecret>, unknown_webhooks: UnknownWebhookPolicy) -> SecretStore {
+        SecretStore {
+            default,
+            unknown_webhooks,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_webhook(
+        mut self,
+        webhook_id: impl Into<String>,
+        secrets: Vec<WebhookSecret>,
+    ) -> Self {
+        self.webhooks
+            .insert(webhook_id.into().to_ascii_lowercase(), secrets);
+        self
+    }
+
+    pub fn with_project(
+        mut self,
+        project_slug: impl Into<String>,
+        secrets: Vec<WebhookSecret>,
+    ) -> Self {
+        self.projects
+            .insert(project_key(&project_slug.into()), secrets);
+        self
+    }
+
+    /// Whether any secrets are keyed by webhook or project, i.e. whether the
+    /// payload has to be looked at to pick them.
+    pub fn is_keyed(&self) -> bool {
+        !self.webhooks.is_empty() || !self.projects.is_empty()
+    }
+
+    /// The secrets for a payload, or `None` if it should be rejected. An empty
+    /// slice means the payload is not verified at all.
+    pub fn secrets_for(
+        &self,
+        webhook_id: Option<&str>,
+        project_slug: Option<&str>,
+    ) -> Option<&[WebhookSecret]> {
+        webhook_id
+            .and_then(|id| self.webhooks.get(&id.to_ascii_lowercase()))
+            .or_else(|| project_slug.and_then(|slug| self.projects.get(&project_key(slug))))
+            .map(Vec::as_slice)
+            .or(match self.unknown_webhooks {
+                UnknownWebhookPolicy::Reject => None,
+                UnknownWebhookPolicy::Default => Some(&self.default),
+            })
+    }
+}
+
+/// Project slugs compare case-insensitively, and the short `gh/` and `bb/`
+/// forms of the CircleCI UI match the `github/` and `bitbucket/` ones of the
+/// payloads.
+pub fn project_key(project_slug: &str) -> String {
+    let slug = project_slug.to_ascii_lowercase();
+    match slug.split_once('/') {
+        Some(("gh", rest)) => format!("github/{}", rest),
+        Some(("bb", rest)) => format!("bitbucket/{}", rest),
+        _ => slug,
+    }
+}
+
+/// Checks the signature against all `secrets` and returns the one that
+/// matched. Every secret is checked, in constant time, so the timing does not
+/// reveal which one matched. Fails if the signature is not valid hex.
+pub fn verify_signature<'a>(
+    body: &[u8],
+    secrets: &'a [WebhookSecret],
+    signature_hex: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<&'a WebhookSecret>, FromHexError> {
+    let signature = hex::decode(signature_hex)?;
+    let mut matched = None;
+    for secret in secrets {
+        let mut mac = HmacSha256::new_from_slice(secret.value.as_bytes())
+            .expect("HMAC can take key of any size");
+        mac.update(body);
+        let result = mac.finalize_fixed();
+        if bool::from(result.ct_eq(&signature)) && matched.is_none() {
+            matched = Some(secret);
+        }
+    }
+
+    Ok(match matched {
+        Some(secret) if secret.is_expired(now) => {
+            warn!(
+                "FAILED signature verification: secret {:?} has expired",
+                secret.name
+            );
+            metrics::signature_matched(&secret.name, true);
+            None
+        }
+        Some(secret) => {
+            debug!(
+                "Verified a body of {} bytes with secret {:?}",
+                body.len(),
+                secret.name
+            );
+            metrics::signature_matched(&secret.name, false);
+            Some(secret)
+        }
+        None => {
+            warn!(
+                "FAILED signature verification against {} secrets",
+                secrets.len()
+            );
+            None
+        }
+    })
 }
 
 #[cfg(test)]
@@ -31,42 +201,178 @@ mod verification_tests {
     // lalala	another-secret	daa220016c8f29a8b214fbfc3671aeec2145cfb1e6790184ffb38b6d0425fa00
     // an-important-request-payload	hunter123	9be2242094a9a8c00c64306f382a7f9d691de910b4a266f67bd314ef18ac49fa
 
-    use super::verify_signature;
+    use chrono::{Duration, Utc};
+
+    use super::{verify_signature, WebhookSecret};
+
+    fn secrets() -> Vec<WebhookSecret> {
+        vec![
+            WebhookSecret::new("current", "hunter123", None),
+            WebhookSecret::new(
+                "previous",
+                "another-secret",
+                Some(Utc::now() + Duration::days(1)),
+            ),
+            WebhookSecret::new("retired", "secret", Some(Utc::now() - Duration::days(1))),
+        ]
+    }
 
     #[test]
     fn test_hashes() {
+        let secrets = vec![WebhookSecret::new("docs", "secret", None)];
         assert!(verify_signature(
             b"hello world",
-            b"secret",
-            "734cc62f32841568f45715aeb9f4d7891324e6d948e4c6c60c0621cdac48623a".to_string()
-        ));
+            &secrets,
+            "734cc62f32841568f45715aeb9f4d7891324e6d948e4c6c60c0621cdac48623a",
+            Utc::now()
+        )
+        .unwrap()
+        .is_some());
+        let secrets = vec![WebhookSecret::new("docs", "another-secret", None)];
         assert!(verify_signature(
             b"lalala",
-            b"another-secret",
-            "daa220016c8f29a8b214fbfc3671aeec2145cfb1e6790184ffb38b6d0425fa00".to_string()
-        ));
+            &secrets,
+            "daa220016c8f29a8b214fbfc3671aeec2145cfb1e6790184ffb38b6d0425fa00",
+            Utc::now()
+        )
+        .unwrap()
+        .is_some());
+        let secrets = vec![WebhookSecret::new("docs", "hunter123", None)];
         assert!(verify_signature(
             b"an-important-request-payload",
-            b"hunter123",
-            "9be2242094a9a8c00c64306f382a7f9d691de910b4a266f67bd314ef18ac49fa".to_string()
-        ));
+            &secrets,
+            "9be2242094a9a8c00c64306f382a7f9d691de910b4a266f67bd314ef18ac49fa",
+            Utc::now()
+        )
+        .unwrap()
+        .is_some());
+    }
+
+    #[test]
+    fn test_matches_any_secret() {
+        let secrets = secrets();
+        let matched = verify_signature(
+            b"lalala",
+            &secrets,
+            "daa220016c8f29a8b214fbfc3671aeec2145cfb1e6790184ffb38b6d0425fa00",
+            Utc::now(),
+        )
+        .unwrap();
+        assert_eq!(matched.map(|secret| secret.name.as_str()), Some("previous"));
+    }
+
+    #[test]
+    fn test_expired_secret() {
+        let secrets = secrets();
+        assert!(verify_signature(
+            b"hello world",
+            &secrets,
+            "734cc62f32841568f45715aeb9f4d7891324e6d948e4c6c60c0621cdac48623a",
+            Utc::now()
+        )
+        .unwrap()
+        .is_none());
+    }
+
+    #[test]
+    fn test_no_match() {
+        let secrets = secrets();
+        assert!(verify_signature(
+            b"tampered",
+            &secrets,
+            "734cc62f32841568f45715aeb9f4d7891324e6d948e4c6c60c0621cdac48623a",
+            Utc::now()
+        )
+        .unwrap()
+        .is_none());
+    }
+
+    #[test]
+    fn test_malformed_signature() {
+        let secrets = secrets();
+        assert!(verify_signature(b"hello world", &secrets, "zz", Utc::now()).is_err());
+        assert!(verify_signature(b"hello world", &secrets, "abc", Utc::now()).is_err());
+    }
+
+    #[test]
+    fn test_debug_redacts_value() {
+        assert!(!format!("{:?}", secrets()).contains("hunter123"));
     }
 }
 
-pub fn parse_signature_header(header_value: &str) -> Option<String> {
-    for signature in header_value.split(',') {
-        let splits: Vec<&str> = signature.split('=').collect();
-        if splits.len() != 2 {
-            warn!(
-                "Invalid header `{}`, does contain {} parts!",
-                header_value,
-                splits.len()
-            );
-            return None;
-        }
+#[cfg(test)]
+mod secret_store_tests {
+    use super::{SecretStore, UnknownWebhookPolicy, WebhookSecret};
+
+    fn store(unknown_webhooks: UnknownWebhookPolicy) -> SecretStore {
+        SecretStore::new(
+            vec![WebhookSecret::new("current", "shared", None)],
+            unknown_webhooks,
+        )
+        .with_webhook(
+            "2F9A4F0E-3C0C-4F43-9A6B-3C1B0B6C2D5E",
+            vec![WebhookSecret::new("team-a/current", "a", None)],
+        )
+        .with_project(
+            "gh/example/b",
+            vec![WebhookSecret::new("team-b/current", "b", None)],
+        )
+    }
+
+    fn names(secrets: Option<&[WebhookSecret]>) -> Option<Vec<&str>> {
+        secrets.map(|secrets| secrets.iter().map(|s| s.name.as_str()).collect())
+    }
+
+    #[test]
+    fn test_webhook_before_project() {
+        let store = store(UnknownWebhookPolicy::Reject);
+        assert_eq!(
+            names(store.secrets_for(
+                Some("2f9a4f0e-3c0c-4f43-9a6b-3c1b0b6c2d5e"),
+                Some("gh/example/b")
+            )),
+            Some(vec!["team-a/current"])
+        );
+        assert_eq!(
+            names(store.secrets_for(None, Some("gh/example/b"))),
+            Some(vec!["team-b/current"])
+        );
+    }
+
+    #[test]
+    fn test_unknown_webhook() {
+        assert_eq!(
+            names(store(UnknownWebhookPolicy::Reject).secrets_for(None, Some("gh/example/c"))),
+            None
+        );
+        assert_eq!(
+            names(store(UnknownWebhookPolicy::Default).secrets_for(None, Some("gh/example/c"))),
+            Some(vec!["current"])
+        );
+    }
 
-        if splits[0] == "v1" {
-            return Some(splits[1].to_string());
+    #[test]
+    fn test_payload_slug() {
+        let store = store(UnknownWebhookPolicy::Reject);
+        assert_eq!(
+            names(store.secrets_for(None, Some("github/Example/B"))),
+            Some(vec!["team-b/current"])
+        );
+        assert_eq!(
+            names(store.secrets_for(None, Some("bitbucket/example/b"))),
+            None
+        );
+    }
+}
+
+/// Finds the `v1` signature in a header like `v1=abc,v2=def`. Whitespace
+/// around entries is ignored, as are unknown schemes and malformed entries.
+pub fn parse_signature_header(header_value: &str) -> Option<&str> {
+    for signature in header_value.split(',') {
+        match signature.split_once('=') {
+            Some((scheme, value)) if scheme.trim() == "v1" => return Some(value.trim()),
+            Some(_) => {}
+            None => warn!("Ignoring malformed signature `{}`", signature),
         }
     }
     None
@@ -83,17 +389,87 @@ mod parse_tests {
 
     #[test]
     fn test_only_v1_signature() {
-        assert_eq!(
-            parse_signature_header("v1=foobar"),
-            Some("foobar".to_string())
-        );
+        assert_eq!(parse_signature_header("v1=foobar"), Some("foobar"));
     }
 
     #[test]
     fn test_multiple_signatures() {
         assert_eq!(
             parse_signature_header("v1=foobar,v2=wibble"),
-            Some("foobar".to_string())
+            Some("foobar")
+        );
+    }
+
+    #[test]
+    fn test_whitespace() {
+        assert_eq!(
+            parse_signature_header(" v2=wibble , v1 = foobar "),
+            Some("foobar")
+        );
+    }
+
+    #[test]
+    fn test_after_malformed_entry() {
+        assert_eq!(
+            parse_signature_header("garbage,v0=x=y,v1=foobar"),
+            Some("foobar")
         );
+        assert_eq!(parse_signature_header("garbage"), None);
+    }
+}
+
+#[cfg(test)]
+mod signature_properties {
+    use chrono::Utc;
+    use hmac::{digest::FixedOutput, Mac};
+    use proptest::prelude::*;
+
+    use super::{parse_signature_header, verify_signature, HmacSha256, WebhookSecret};
+
+    fn sign(body: &[u8], key: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(body);
+        hex::encode(mac.finalize_fixed())
+    }
+
+    proptest! {
+        #[test]
+        fn parser_never_panics(header in ".*") {
+            parse_signature_header(&header);
+        }
+
+        #[test]
+        fn parser_finds_v1_among_other_entries(
+            before in prop::collection::vec("[^,]*", 0..4),
+            after in prop::collection::vec("[^,]*", 0..4),
+            signature in "[0-9a-f]{64}",
+            padding in " {0,2}",
+        ) {
+            // an earlier entry that is a v1 signature itself would win
+            prop_assume!(before.iter().all(|entry| parse_signature_header(entry).is_none()));
+            let entry = format!("{}v1={}{}", padding, signature, padding);
+            let header = [before, vec![entry], after].concat().join(",");
+            prop_assert_eq!(parse_signature_header(&header), Some(signature.as_str()));
+        }
+
+        #[test]
+        fn verifier_never_panics(body in any::<Vec<u8>>(), signature in ".*") {
+            let secrets = vec![WebhookSecret::new("current", "secret", None)];
+            let _ = verify_signature(&body, &secrets, &signature, Utc::now());
+        }
+
+        #[test]
+        fn verifier_accepts_only_the_right_key(
+            body in any::<Vec<u8>>(),
+            key in ".+",
+            other_key in ".+",
+        ) {
+            prop_assume!(key != other_key);
+            let signature = sign(&body, &key);
+            let secrets = vec![WebhookSecret::new("current", key, None)];
+            prop_assert!(verify_signature(&body, &secrets, &signature, Utc::now()).unwrap().is_some());
+            let secrets = vec![WebhookSecret::new("other", other_key, None)];
+            prop_assert!(verify_signature(&body, &secrets, &signature, Utc::now()).unwrap().is_none());
+        }
     }
 }
@@ -0,0 +1,161 @@
+//! Helpers to assert on the spans built from webhook payloads, without an
+//! exporter or a collector.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use hmac::{digest::FixedOutput, Hmac, Mac};
+use opentelemetry::{
+    sdk::{
+        export::trace::SpanData,
+        trace::{Span, SpanProcessor, Tracer, TracerProvider},
+    },
+    trace::{Status, TraceResult, TracerProvider as _},
+    Context, Key, Value,
+};
+
+/// Records every span when it ends. Unlike the SDK's processors this happens
+/// synchronously, so the spans are available as soon as `handle_hook` returns.
+#[derive(Clone, Debug, Default)]
+pub struct InMemorySpans {
+    spans: Arc<Mutex<Vec<SpanData>>>,
+}
+
+impl InMemorySpans {
+    /// The spans that have ended so far, in the order they ended.
+    pub fn spans(&self) -> Vec<SpanData> {
+        self.spans.lock().unwrap().clone()
+    }
+
+    /// The span called `name`. Panics if there is not exactly one.
+    pub fn span(&self, name: &str) -> SpanData {
+        let mut spans = self.spans();
+        spans.retain(|span| span.name == name);
+        match spans.len() {
+            1 => spans.remove(0),
+            n => panic!("expected one span named {:?}, found {}", name, n),
+        }
+    }
+
+    /// The spans in a form that prints the same on every run.
+    pub fn snapshot(&self) -> Vec<SpanSnapshot> {
+        self.spans().iter().map(SpanSnapshot::from).collect()
+    }
+
+    pub fn clear(&self) {
+        self.spans.lock().unwrap().clear();
+    }
+}
+
+impl SpanProcessor for InMemorySpans {
+    fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        self.spans.lock().unwrap().push(span);
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        Ok(())
+    }
+}
+
+/// A tracer whose spans end up in an [`InMemorySpans`].
+#[derive(Debug)]
+pub struct TestTracer {
+    // the tracer only holds a weak reference to its provider
+    _provider: TracerProvider,
+    tracer: Tracer,
+    spans: InMemorySpans,
+}
+
+impl TestTracer {
+    pub fn new() -> TestTracer {
+        let spans = InMemorySpans::default();
+        let provider = TracerProvider::builder()
+            .with_span_processor(spans.clone())
+            .build();
+        let tracer = provider.tracer("circleci-hook-test");
+        TestTracer {
+            _provider: provider,
+            tracer,
+            spans,
+        }
+    }
+
+    pub fn tracer(&self) -> &Tracer {
+        &self.tracer
+    }
+
+    pub fn spans(&self) -> &InMemorySpans {
+        &self.spans
+    }
+}
+
+impl Default for TestTracer {
+    fn default() -> Self {
+        TestTracer::new()
+    }
+}
+
+/// The `circleci-signature` header CircleCI sends with `body`, signed with
+/// `secret`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC can take key of any size");
+    mac.update(body);
+    format!("v1={}", hex::encode(mac.finalize_fixed()))
+}
+
+/// The value of the attribute `key` of `span`, if it is set.
+pub fn attribute(span: &SpanData, key: &'static str) -> Option<Value> {
+    span.attributes.get(&Key::from_static_str(key)).cloned()
+}
+
+/// The parts of a span that are derived from the payload, with the attributes
+/// sorted and the timestamps formatted, for snapshot tests.
+#[derive(Debug)]
+pub struct SpanSnapshot {
+    pub name: String,
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: String,
+    pub start_time: String,
+    pub end_time: String,
+    pub status: Status,
+    /// Strings are quoted, so they can be told apart from other values.
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl From<&SpanData> for SpanSnapshot {
+    fn from(span: &SpanData) -> Self {
+        let timestamp =
+            |time| DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::AutoSi, true);
+        SpanSnapshot {
+            name: span.name.to_string(),
+            trace_id: span.span_context.trace_id().to_string(),
+            span_id: span.span_context.span_id().to_string(),
+            parent_span_id: span.parent_span_id.to_string(),
+            start_time: timestamp(span.start_time),
+            end_time: timestamp(span.end_time),
+            status: span.status.clone(),
+            attributes: span
+                .attributes
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        Value::String(value) => format!("{:?}", value.as_str()),
+                        value => value.to_string(),
+                    };
+                    (key.to_string(), value)
+                })
+                .collect(),
+        }
+    }
+}
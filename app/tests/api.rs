@@ -0,0 +1,422 @@
+//! Runs `handle_hook` against a stub of the CircleCI API.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{extract::Query, http::HeaderMap, http::StatusCode, routing::get, Router};
+use circleci_hook_app::{
+    api::{Client, TestSpans},
+    critical_path::CriticalPath,
+    handle_hook,
+    testing::{attribute, TestTracer},
+    Hook,
+};
+use opentelemetry::{
+    trace::{SpanId, Status},
+    Array, KeyValue, StringValue, Value,
+};
+
+const TOKEN: &str = "stub-token";
+
+fn authorized(headers: &HeaderMap) -> Result<(), StatusCode> {
+    match headers.get("circle-token") {
+        Some(token) if token == TOKEN => Ok(()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+async fn job_details(headers: HeaderMap) -> Result<&'static str, StatusCode> {
+    authorized(&headers)?;
+    Ok(include_str!("../testdata/api/job-details.json"))
+}
+
+async fn tests(
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<&'static str, StatusCode> {
+    authorized(&headers)?;
+    Ok(match query.get("page-token") {
+        None => include_str!("../testdata/api/tests.json"),
+        Some(_) => include_str!("../testdata/api/tests-2.json"),
+    })
+}
+
+async fn artifacts(headers: HeaderMap) -> Result<&'static str, StatusCode> {
+    authorized(&headers)?;
+    Ok(include_str!("../testdata/api/artifacts.json"))
+}
+
+async fn workflow_jobs(headers: HeaderMap) -> Result<&'static str, StatusCode> {
+    authorized(&headers)?;
+    Ok(include_str!("../testdata/api/workflow-jobs.json"))
+}
+
+/// Serves the API on a free port, returning its base url.
+fn stub_api() -> String {
+    let app = Router::new()
+        .route(
+            "/api/v1.1/project/github/DavidS/circleci-hook/10",
+            get(job_details),
+        )
+        .route(
+            "/api/v2/project/github/DavidS/circleci-hook/10/tests",
+            get(tests),
+        )
+        .route(
+            "/api/v2/project/github/DavidS/circleci-hook/10/artifacts",
+            get(artifacts),
+        )
+        .route(
+            "/api/v2/workflow/410c427b-40a8-4bb4-9d42-5561f5bce5ba/job",
+            get(workflow_jobs),
+        );
+    serve(app)
+}
+
+/// Serves the steps of the job, taking `delay` to answer the first request.
+fn slow_api(delay: Duration) -> String {
+    let slow = Arc::new(AtomicBool::new(true));
+    let app = Router::new().route(
+        "/api/v1.1/project/github/DavidS/circleci-hook/10",
+        get(move |headers: HeaderMap| async move {
+            if slow.swap(false, Ordering::SeqCst) {
+                tokio::time::sleep(delay).await;
+            }
+            job_details(headers).await
+        }),
+    );
+    serve(app)
+}
+
+/// Serves the jobs of the workflow, taking `delay` to answer.
+fn slow_workflow_jobs(delay: Duration) -> String {
+    let app = Router::new().route(
+        "/api/v2/workflow/410c427b-40a8-4bb4-9d42-5561f5bce5ba/job",
+        get(move |headers: HeaderMap| async move {
+            tokio::time::sleep(delay).await;
+            workflow_jobs(headers).await
+        }),
+    );
+    serve(app)
+}
+
+fn serve(app: Router) -> String {
+    let server =
+        axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(app.into_make_service());
+    let base_url = format!("http://{}/", server.local_addr());
+    tokio::spawn(server);
+    base_url
+}
+
+fn client(base_url: &str, token: &str) -> Client {
+    Client::new(
+        base_url.parse().unwrap(),
+        Some(token.to_owned()),
+        Duration::from_secs(5),
+    )
+    .unwrap()
+    .with_steps(true)
+    .with_tests(true, TestSpans::default())
+    .with_artifacts(true, 20)
+    .with_dependencies(true)
+}
+
+#[tokio::test]
+async fn test_steps() {
+    let tracer = TestTracer::new();
+    let hook = Hook::new(tracer.tracer().clone()).with_api(client(&stub_api(), TOKEN));
+    let body = include_bytes!("../testdata/job-completed.json");
+    handle_hook(&hook, body).await.unwrap();
+
+    let spans = tracer.spans();
+    let job = spans.span("job: rust/lint-test-build");
+    let step = spans.span("step: cargo test");
+    assert_eq!(step.parent_span_id, job.span_context.span_id());
+    assert_eq!(step.span_context.trace_id(), job.span_context.trace_id());
+    assert_eq!(step.status, Status::Ok);
+    assert_eq!(
+        attribute(&step, "circleci.step.number"),
+        Some(Value::I64(102))
+    );
+
+    let node = spans.span("step: cargo test [node 1]");
+    assert_eq!(node.parent_span_id, step.span_context.span_id());
+    assert_eq!(attribute(&node, "circleci.step.node"), Some(Value::I64(1)));
+    assert_eq!(
+        attribute(&node, "circleci.step.exit_code"),
+        Some(Value::I64(0))
+    );
+    assert!(node.end_time < step.end_time);
+    // the job, three steps and two nodes each
+    assert_eq!(spans.spans().len(), 10);
+}
+
+#[tokio::test]
+async fn test_tests() {
+    let tracer = TestTracer::new();
+    let test_spans = TestSpans {
+        failed: true,
+        slower_than: Some(4.0),
+    };
+    let api = client(&stub_api(), TOKEN)
+        .with_steps(false)
+        .with_tests(true, test_spans);
+    let hook = Hook::new(tracer.tracer().clone()).with_api(api);
+    let body = include_bytes!("../testdata/job-completed.json");
+    handle_hook(&hook, body).await.unwrap();
+
+    let spans = tracer.spans();
+    let job = spans.span("job: rust/lint-test-build");
+    assert_eq!(attribute(&job, "circleci.tests.total"), Some(Value::I64(6)));
+    assert_eq!(
+        attribute(&job, "circleci.tests.failed"),
+        Some(Value::I64(1))
+    );
+    assert_eq!(
+        attribute(&job, "circleci.tests.skipped"),
+        Some(Value::I64(1))
+    );
+    assert_eq!(
+        attribute(&job, "circleci.tests.slowest.name"),
+        Some(Value::from("signature_properties"))
+    );
+
+    let failed = spans.span("test: test_invalid_signature");
+    assert_eq!(failed.parent_span_id, job.span_context.span_id());
+    assert!(matches!(failed.status, Status::Error { .. }));
+    assert_eq!(
+        attribute(&failed, "test.case.result.status"),
+        Some(Value::from("fail"))
+    );
+    let slow = spans.span("test: signature_properties");
+    assert_eq!(slow.start_time, job.start_time);
+    assert_eq!(
+        slow.end_time.duration_since(slow.start_time).unwrap(),
+        Duration::from_millis(4812)
+    );
+    assert_eq!(spans.spans().len(), 3);
+}
+
+#[tokio::test]
+async fn test_artifacts() {
+    let tracer = TestTracer::new();
+    let api = client(&stub_api(), TOKEN)
+        .with_steps(false)
+        .with_tests(false, TestSpans::default())
+        .with_artifacts(true, 2);
+    let hook = Hook::new(tracer.tracer().clone()).with_api(api);
+    let body = include_bytes!("../testdata/job-completed.json");
+    handle_hook(&hook, body).await.unwrap();
+
+    let job = tracer.spans().span("job: rust/lint-test-build");
+    assert_eq!(
+        attribute(&job, "circleci.artifacts.count"),
+        Some(Value::I64(3))
+    );
+    assert_eq!(
+        attribute(&job, "circleci.artifacts.path"),
+        Some(Value::Array(
+            vec![
+                StringValue::from("coverage/index.html"),
+                StringValue::from("target/nextest/junit.xml"),
+            ]
+            .into()
+        ))
+    );
+    let Some(Value::Array(Array::String(urls))) = attribute(&job, "circleci.artifacts.url") else {
+        panic!("expected a list of urls");
+    };
+    assert_eq!(urls.len(), 2);
+    assert!(urls[0].as_str().ends_with("/0/coverage/index.html"));
+}
+
+#[tokio::test]
+async fn test_dependencies() {
+    let tracer = TestTracer::new();
+    let api = client(&stub_api(), TOKEN)
+        .with_steps(false)
+        .with_tests(false, TestSpans::default())
+        .with_artifacts(false, 0);
+    let hook = Hook::new(tracer.tracer().clone()).with_api(api);
+    let body = include_bytes!("../testdata/job-completed.json");
+    handle_hook(&hook, body).await.unwrap();
+
+    let job = tracer.spans().span("job: rust/lint-test-build");
+    let links: Vec<_> = job.links.iter().collect();
+    assert_eq!(links.len(), 2);
+    assert_eq!(
+        links[0].span_context.trace_id(),
+        job.span_context.trace_id()
+    );
+    assert_eq!(
+        links[0].span_context.span_id(),
+        SpanId::from_hex("5b3a7f2e1c0d4f8e").unwrap()
+    );
+    assert_eq!(
+        links[1].attributes,
+        [
+            KeyValue::new("circleci.job.name", "rust/fmt"),
+            KeyValue::new("circleci.job.status", "success"),
+        ]
+    );
+}
+
+/// Holds back the job spans, with nothing else fetched about the jobs.
+fn critical_path_hook(tracer: &TestTracer) -> Hook {
+    let api = client(&stub_api(), TOKEN)
+        .with_steps(false)
+        .with_tests(false, TestSpans::default())
+        .with_artifacts(false, 0)
+        .with_dependencies(false);
+    Hook::new(tracer.tracer().clone())
+        .with_api(api)
+        .with_critical_path(CriticalPath::default())
+}
+
+#[tokio::test]
+async fn test_critical_path() {
+    let tracer = TestTracer::new();
+    let hook = critical_path_hook(&tracer);
+    let body = include_bytes!("../testdata/job-completed.json");
+    handle_hook(&hook, body).await.unwrap();
+    // held back until the workflow completes
+    assert!(tracer.spans().spans().is_empty());
+
+    let body = include_bytes!("../testdata/workflow-completed.json");
+    handle_hook(&hook, body).await.unwrap();
+    let spans = tracer.spans();
+    assert_eq!(spans.spans().len(), 2);
+    let job = spans.span("job: rust/lint-test-build");
+    assert_eq!(
+        attribute(&job, "circleci.job.critical_path"),
+        Some(Value::Bool(true))
+    );
+    assert_eq!(attribute(&job, "circleci.job.slack"), Some(Value::F64(0.0)));
+    let workflow = spans.span("workflow: production");
+    assert_eq!(job.parent_span_id, workflow.span_context.span_id());
+}
+
+#[tokio::test]
+async fn test_late_job() {
+    let tracer = TestTracer::new();
+    let hook = critical_path_hook(&tracer);
+    let body = include_bytes!("../testdata/workflow-completed.json");
+    handle_hook(&hook, body).await.unwrap();
+    let body = include_bytes!("../testdata/job-completed.json");
+    handle_hook(&hook, body).await.unwrap();
+
+    // exported right away, as its workflow won't complete again
+    let job = tracer.spans().span("job: rust/lint-test-build");
+    assert_eq!(attribute(&job, "circleci.job.critical_path"), None);
+}
+
+#[tokio::test]
+async fn test_flush() {
+    let tracer = TestTracer::new();
+    let hook = critical_path_hook(&tracer);
+    let body = include_bytes!("../testdata/job-completed.json");
+    handle_hook(&hook, body).await.unwrap();
+    assert!(tracer.spans().spans().is_empty());
+
+    // as on shutdown
+    hook.flush();
+    let job = tracer.spans().span("job: rust/lint-test-build");
+    assert_eq!(attribute(&job, "circleci.job.critical_path"), None);
+}
+
+#[tokio::test]
+async fn test_timeout() {
+    let tracer = TestTracer::new();
+    let api = Client::new(
+        slow_api(Duration::from_secs(5)).parse().unwrap(),
+        Some(TOKEN.to_owned()),
+        Duration::from_millis(200),
+    )
+    .unwrap()
+    .with_steps(true);
+    let hook = Hook::new(tracer.tracer().clone()).with_api(api);
+    let body = include_bytes!("../testdata/job-completed.json");
+    let started = Instant::now();
+    handle_hook(&hook, body).await.unwrap();
+
+    // the job is exported without its steps once the timeout passed
+    assert!(started.elapsed() < Duration::from_secs(2));
+    let spans = tracer.spans().spans();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].name, "job: rust/lint-test-build");
+}
+
+#[tokio::test]
+async fn test_workflow_jobs_timeout() {
+    let tracer = TestTracer::new();
+    let api = Client::new(
+        slow_workflow_jobs(Duration::from_secs(5)).parse().unwrap(),
+        Some(TOKEN.to_owned()),
+        Duration::from_millis(200),
+    )
+    .unwrap();
+    let hook = Hook::new(tracer.tracer().clone())
+        .with_api(api)
+        .with_critical_path(CriticalPath::default());
+    handle_hook(&hook, include_bytes!("../testdata/job-completed.json"))
+        .await
+        .unwrap();
+    let started = Instant::now();
+    handle_hook(&hook, include_bytes!("../testdata/workflow-completed.json"))
+        .await
+        .unwrap();
+
+    // the held job is exported without its slack once the timeout passed
+    assert!(started.elapsed() < Duration::from_secs(2));
+    let spans = tracer.spans();
+    assert_eq!(spans.spans().len(), 2);
+    let job = spans.span("job: rust/lint-test-build");
+    assert_eq!(attribute(&job, "circleci.job.slack"), None);
+}
+
+#[tokio::test]
+async fn test_redelivery_after_request_timeout() {
+    let tracer = TestTracer::new();
+    let api = client(&slow_api(Duration::from_secs(2)), TOKEN)
+        .with_tests(false, TestSpans::default())
+        .with_artifacts(false, 0)
+        .with_dependencies(false);
+    let hook = Hook::new(tracer.tracer().clone()).with_api(api);
+    let body = include_bytes!("../testdata/job-completed.json");
+    // the server gives up on the request while the API is still looked up
+    let request = tokio::time::timeout(Duration::from_millis(200), handle_hook(&hook, body));
+    assert!(request.await.is_err());
+    assert!(tracer.spans().spans().is_empty());
+
+    assert_eq!(handle_hook(&hook, body).await.unwrap(), "Success!");
+    let spans = tracer.spans();
+    spans.span("job: rust/lint-test-build");
+    spans.span("step: cargo test");
+    assert_eq!(
+        handle_hook(&hook, body).await.unwrap(),
+        "Ignored duplicate event"
+    );
+}
+
+#[tokio::test]
+async fn test_api_failure() {
+    let tracer = TestTracer::new();
+    let hook = Hook::new(tracer.tracer().clone()).with_api(client(&stub_api(), "wrong-token"));
+    let body = include_bytes!("../testdata/job-completed.json");
+    handle_hook(&hook, body).await.unwrap();
+
+    // the job is exported without its steps and tests
+    let spans = tracer.spans().spans();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].name, "job: rust/lint-test-build");
+    assert_eq!(attribute(&spans[0], "circleci.tests.total"), None);
+    assert_eq!(attribute(&spans[0], "circleci.artifacts.count"), None);
+    assert_eq!(spans[0].links.len(), 0);
+}
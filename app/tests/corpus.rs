@@ -0,0 +1,35 @@
+//! Snapshot tests over the payloads in `testdata/`. After an intended change,
+//! review and accept the new snapshots with `cargo insta review`.
+//!
+//! Only `job-completed.json`, `workflow-completed.json` and `ping.json` are
+//! deliveries recorded from CircleCI. The other payloads are synthetic, see
+//! `testdata/README.md`, so they may differ from what CircleCI actually sends.
+
+use std::fs;
+
+use circleci_hook_app::{
+    api::JobDetails, payload::WebhookPayload, semconv::AttributeMode, testing::TestTracer,
+};
+
+#[test]
+fn test_payloads() {
+    insta::glob!("../testdata", "*.json", |path| {
+        let body = fs::read(path).unwrap();
+        let payload = WebhookPayload::from_slice(&body).unwrap();
+        insta::assert_debug_snapshot!("payload", payload);
+    });
+}
+
+#[test]
+fn test_spans() {
+    insta::glob!("../testdata", "*.json", |path| {
+        let body = fs::read(path).unwrap();
+        let tracer = TestTracer::new();
+        WebhookPayload::from_slice(&body).unwrap().build_span(
+            tracer.tracer(),
+            AttributeMode::Both,
+            &JobDetails::default(),
+        );
+        insta::assert_debug_snapshot!("spans", tracer.spans().snapshot());
+    });
+}
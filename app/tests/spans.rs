@@ -0,0 +1,147 @@
+use std::time::SystemTime;
+
+use chrono::DateTime;
+use circleci_hook_app::{
+    handle_hook,
+    semconv::AttributeMode,
+    testing::{attribute, TestTracer},
+    Hook,
+};
+use opentelemetry::{
+    trace::{SpanId, Status, TraceId},
+    Value,
+};
+use uuid::Uuid;
+
+const WORKFLOW_ID: &str = "410c427b-40a8-4bb4-9d42-5561f5bce5ba";
+const JOB_ID: &str = "20e45d7e-e4a7-4aa3-8f92-fd6d9d01da75";
+
+fn time(rfc3339: &str) -> SystemTime {
+    DateTime::parse_from_rfc3339(rfc3339).unwrap().into()
+}
+
+fn span_id(uuid: &str) -> SpanId {
+    let bytes = *Uuid::parse_str(uuid).unwrap().as_bytes();
+    SpanId::from_bytes(bytes[..8].try_into().unwrap())
+}
+
+fn trace_id(uuid: &str) -> TraceId {
+    TraceId::from_bytes(*Uuid::parse_str(uuid).unwrap().as_bytes())
+}
+
+#[tokio::test]
+async fn test_workflow_completed() {
+    let tracer = TestTracer::new();
+    let body = include_bytes!("../testdata/workflow-completed.json");
+    let result = handle_hook(&Hook::new(tracer.tracer().clone()), body).await;
+    assert_eq!(result.unwrap(), "Success!");
+
+    let span = tracer.spans().span("workflow: production");
+    assert_eq!(span.span_context.trace_id(), trace_id(WORKFLOW_ID));
+    assert_eq!(span.span_context.span_id(), span_id(WORKFLOW_ID));
+    assert_eq!(span.parent_span_id, SpanId::INVALID);
+    assert_eq!(span.start_time, time("2022-08-27T20:25:40.675Z"));
+    assert_eq!(span.end_time, time("2022-08-27T20:26:31.289Z"));
+    assert_eq!(span.status, Status::Ok);
+    assert_eq!(
+        attribute(&span, "circleci.kind"),
+        Some(Value::from("workflow"))
+    );
+    assert_eq!(
+        attribute(&span, "circleci.workflow.status"),
+        Some(Value::from("success"))
+    );
+    assert_eq!(
+        attribute(&span, "circleci.pipeline.number"),
+        Some(Value::I64(10))
+    );
+    assert_eq!(attribute(&span, "circleci.stopped_at.fallback"), None);
+    assert_eq!(
+        attribute(&span, "cicd.pipeline.run.id"),
+        Some(Value::from(WORKFLOW_ID))
+    );
+    assert_eq!(
+        attribute(&span, "cicd.pipeline.result"),
+        Some(Value::from("success"))
+    );
+}
+
+#[tokio::test]
+async fn test_job_completed() {
+    let tracer = TestTracer::new();
+    let body = include_bytes!("../testdata/job-completed.json");
+    let result = handle_hook(&Hook::new(tracer.tracer().clone()), body).await;
+    assert_eq!(result.unwrap(), "Success!");
+
+    let span = tracer.spans().span("job: rust/lint-test-build");
+    assert_eq!(span.span_context.trace_id(), trace_id(WORKFLOW_ID));
+    assert_eq!(span.span_context.span_id(), span_id(JOB_ID));
+    assert_eq!(span.parent_span_id, span_id(WORKFLOW_ID));
+    assert_eq!(span.start_time, time("2022-08-27T20:25:43.007Z"));
+    assert_eq!(span.end_time, time("2022-08-27T20:26:31.289Z"));
+    assert_eq!(span.status, Status::Ok);
+    assert_eq!(attribute(&span, "circleci.kind"), Some(Value::from("job")));
+    assert_eq!(
+        attribute(&span, "circleci.job.name"),
+        Some(Value::from("rust/lint-test-build"))
+    );
+    assert_eq!(
+        attribute(&span, "circleci.job.status"),
+        Some(Value::from("success"))
+    );
+    assert_eq!(
+        attribute(&span, "circleci.workflow.id"),
+        Some(Value::from(format!("urn:uuid:{}", WORKFLOW_ID)))
+    );
+    assert_eq!(
+        attribute(&span, "cicd.pipeline.task.name"),
+        Some(Value::from("rust/lint-test-build"))
+    );
+    assert_eq!(
+        attribute(&span, "cicd.pipeline.task.run.url.full"),
+        Some(Value::from(
+            "https://app.circleci.com/pipelines/github/DavidS/circleci-hook/10/workflows/410c427b-40a8-4bb4-9d42-5561f5bce5ba/jobs/10"
+        ))
+    );
+}
+
+#[tokio::test]
+async fn test_attribute_modes() {
+    let body = include_bytes!("../testdata/job-completed.json");
+    for (mode, legacy, semconv) in [
+        (AttributeMode::Legacy, true, false),
+        (AttributeMode::Semconv, false, true),
+        (AttributeMode::Both, true, true),
+    ] {
+        let tracer = TestTracer::new();
+        let hook = Hook::new(tracer.tracer().clone()).with_attributes(mode);
+        handle_hook(&hook, body).await.unwrap();
+        let span = tracer.spans().span("job: rust/lint-test-build");
+        assert_eq!(attribute(&span, "circleci.job.status").is_some(), legacy);
+        assert_eq!(attribute(&span, "vcs.branch").is_some(), legacy);
+        assert_eq!(
+            attribute(&span, "circleci.organization.name").is_some(),
+            legacy
+        );
+        assert_eq!(attribute(&span, "circleci.project.name").is_some(), legacy);
+        assert_eq!(
+            attribute(&span, "cicd.pipeline.task.run.result").is_some(),
+            semconv
+        );
+        assert_eq!(attribute(&span, "vcs.ref.head.name").is_some(), semconv);
+        // no counterpart in the conventions
+        assert!(attribute(&span, "circleci.job.number").is_some());
+        assert!(attribute(&span, "circleci.project.slug").is_some());
+    }
+}
+
+#[tokio::test]
+async fn test_duplicate_delivery() {
+    let tracer = TestTracer::new();
+    let hook = Hook::new(tracer.tracer().clone());
+    let body = include_bytes!("../testdata/job-completed.json");
+    handle_hook(&hook, body).await.unwrap();
+    let result = handle_hook(&hook, body).await;
+    assert_eq!(result.unwrap(), "Ignored duplicate event");
+    assert_eq!(tracer.spans().spans().len(), 1);
+}
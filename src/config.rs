@@ -0,0 +1,1174 @@
+//! The server configuration. Settings are read from an optional TOML or YAML
+//! file, then overridden by environment variables, then by command line
+//! arguments.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs, io,
+    net::{IpAddr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use circleci_hook_app::{
+    api,
+    critical_path::{self, CriticalPath},
+    middleware,
+    replay::{self, ReplayGuard},
+    semconv::AttributeMode,
+    signatures::{self, SecretStore, UnknownWebhookPolicy, WebhookSecret},
+};
+use clap::{Parser, ValueEnum};
+use opentelemetry::{sdk::Resource, KeyValue};
+use opentelemetry_otlp::{
+    OTEL_EXPORTER_OTLP_ENDPOINT, OTEL_EXPORTER_OTLP_TIMEOUT, OTEL_EXPORTER_OTLP_TIMEOUT_DEFAULT,
+};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use thiserror::Error;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use url::Url;
+
+const ENDPOINT: &str = "CIRCLECI_OTLP_ENDPOINT";
+const HEADER_PREFIX: &str = "CIRCLECI_OTLP_";
+const SECRET_TOKEN: &str = "CIRCLECI_HOOK_SECRET";
+const PREVIOUS_SECRET_TOKEN: &str = "CIRCLECI_HOOK_SECRET_PREVIOUS";
+const SERVICE_NAME: &str = "CIRCLECI_HOOK_SERVICE";
+const OTEL_EXPORTER_OTLP_HEADERS: &str = "OTEL_EXPORTER_OTLP_HEADERS";
+const OTEL_SERVICE_NAME: &str = "OTEL_SERVICE_NAME";
+const API_TOKEN: &str = "CIRCLECI_TOKEN";
+const OTEL_SEMCONV_STABILITY_OPT_IN: &str = "OTEL_SEMCONV_STABILITY_OPT_IN";
+const OTLP_HTTP_ENDPOINT_DEFAULT: &str = "http://localhost:4318";
+// plaintext like the http default, as TLS depends on the scheme
+const OTLP_GRPC_ENDPOINT_DEFAULT: &str = "http://localhost:4317";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read {path:?}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("failed to parse {path:?}: {message}")]
+    Parse { path: PathBuf, message: String },
+    #[error("unsupported format for {0:?}, use a .toml, .yaml or .yml file")]
+    Format(PathBuf),
+    #[error("invalid {name}: {message}")]
+    Invalid { name: String, message: String },
+}
+
+impl ConfigError {
+    fn invalid(name: impl Into<String>, message: impl ToString) -> ConfigError {
+        ConfigError::Invalid {
+            name: name.into(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Receives CircleCI webhook events and forwards them as OpenTelemetry traces.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    /// A TOML or YAML configuration file.
+    #[arg(long, env = "CIRCLECI_HOOK_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Validate the configuration and exit.
+    #[arg(long)]
+    pub check_config: bool,
+    /// The address to listen on. [default: ::]
+    #[arg(long, env = "CIRCLECI_HOOK_ADDRESS")]
+    address: Option<IpAddr>,
+    /// The port to listen on. [default: 3000]
+    #[arg(long, env = "CIRCLECI_HOOK_PORT")]
+    port: Option<u16>,
+    /// The protocol used to send traces to the collector. [default: grpc]
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_PROTOCOL", value_enum)]
+    otlp_protocol: Option<Protocol>,
+    #[command(flatten)]
+    tls: TlsArgs,
+}
+
+/// TLS settings for the connection to the collector. Whether TLS is used at
+/// all is decided by the scheme of the endpoint.
+#[derive(clap::Args, Debug)]
+struct TlsArgs {
+    /// A PEM file with additional CA certificates to trust for the collector.
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_CERTIFICATE")]
+    otlp_certificate: Option<PathBuf>,
+    /// A PEM file with the client certificate to authenticate to the collector.
+    #[arg(
+        long,
+        env = "OTEL_EXPORTER_OTLP_CLIENT_CERTIFICATE",
+        requires = "otlp_client_key"
+    )]
+    otlp_client_certificate: Option<PathBuf>,
+    /// A PEM file with the private key of the client certificate.
+    #[arg(
+        long,
+        env = "OTEL_EXPORTER_OTLP_CLIENT_KEY",
+        requires = "otlp_client_certificate"
+    )]
+    otlp_client_key: Option<PathBuf>,
+    /// The domain name to expect in the collector's certificate, instead of the host of the endpoint. Only supported for grpc.
+    #[arg(long, env = "CIRCLECI_HOOK_OTLP_DOMAIN_NAME")]
+    otlp_domain_name: Option<String>,
+}
+
+/// The transport and encoding used to send data to the collector.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+pub enum Protocol {
+    #[default]
+    #[value(name = "grpc")]
+    #[serde(rename = "grpc")]
+    Grpc,
+    #[value(name = "http/protobuf")]
+    #[serde(rename = "http/protobuf")]
+    HttpProtobuf,
+    #[value(name = "http/json")]
+    #[serde(rename = "http/json")]
+    HttpJson,
+}
+
+/// A secret value that is kept out of debug output and logs.
+#[derive(Clone, Deserialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret([redacted])")
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Listen,
+    pub secrets: Secrets,
+    pub exporter: Exporter,
+    /// Attributes of the OpenTelemetry resource, e.g. `service.name`.
+    pub resource: HashMap<String, String>,
+    pub features: Features,
+    pub replay: Replay,
+    pub limits: Limits,
+    pub api: Api,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Listen {
+    pub address: IpAddr,
+    pub port: u16,
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Listen {
+            address: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            port: 3000,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Secrets {
+    /// The secret CircleCI signs the webhook payloads with.
+    pub hook_secret: Option<Secret>,
+    /// Further secrets to accept while rotating, e.g. the previous one.
+    pub previous: Vec<WebhookSecret>,
+    /// Secrets of individual webhooks or projects, used instead of the ones above.
+    pub webhooks: Vec<KeyedSecrets>,
+    /// What to do with payloads from webhooks or projects not in `webhooks`.
+    pub unknown_webhooks: UnknownWebhookPolicy,
+}
+
+/// The secrets of a single webhook, or of all webhooks of a project.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct KeyedSecrets {
+    pub webhook_id: Option<String>,
+    pub project_slug: Option<String>,
+    pub hook_secret: Secret,
+    #[serde(default)]
+    pub previous: Vec<WebhookSecret>,
+}
+
+impl Secrets {
+    pub fn secret_store(&self) -> Result<SecretStore, ConfigError> {
+        if self.hook_secret.is_none() && !self.previous.is_empty() {
+            return Err(ConfigError::invalid(
+                "secrets",
+                "previous secrets are only accepted together with a hook_secret",
+            ));
+        }
+        if self.hook_secret.is_none()
+            && !self.webhooks.is_empty()
+            && self.unknown_webhooks == UnknownWebhookPolicy::Default
+        {
+            return Err(ConfigError::invalid(
+                "secrets.unknown_webhooks",
+                "\"default\" would accept unsigned payloads of the webhooks not in \
+                 secrets.webhooks, set a hook_secret or use \"reject\"",
+            ));
+        }
+        let mut store = SecretStore::new(
+            collect_secrets(None, self.hook_secret.as_ref(), &self.previous)?,
+            self.unknown_webhooks,
+        );
+
+        let mut keys = HashSet::new();
+        for keyed in &self.webhooks {
+            let key = match (&keyed.webhook_id, &keyed.project_slug) {
+                (Some(key), None) | (None, Some(key)) => key,
+                _ => {
+                    return Err(ConfigError::invalid(
+                        "secrets.webhooks",
+                        "set either webhook_id or project_slug",
+                    ))
+                }
+            };
+            let normalized = match keyed.webhook_id {
+                Some(_) => key.to_ascii_lowercase(),
+                None => signatures::project_key(key),
+            };
+            if !keys.insert(normalized) {
+                return Err(ConfigError::invalid(
+                    "secrets.webhooks",
+                    format!("{:?} is configured more than once", key),
+                ));
+            }
+            let secrets = collect_secrets(Some(key), Some(&keyed.hook_secret), &keyed.previous)?;
+            store = match keyed.webhook_id {
+                Some(_) => store.with_webhook(key, secrets),
+                None => store.with_project(key, secrets),
+            };
+        }
+        Ok(store)
+    }
+}
+
+/// The current secret followed by the previous ones. Names are prefixed with
+/// the webhook id or project slug, if any, to tell them apart in metrics.
+fn collect_secrets(
+    key: Option<&str>,
+    hook_secret: Option<&Secret>,
+    previous: &[WebhookSecret],
+) -> Result<Vec<WebhookSecret>, ConfigError> {
+    let name = |name: &str| match key {
+        Some(key) => format!("{}/{}", key, name),
+        None => name.to_string(),
+    };
+    let secrets: Vec<WebhookSecret> = hook_secret
+        .map(|secret| WebhookSecret::new(name("current"), secret.expose(), None))
+        .into_iter()
+        .chain(previous.iter().map(|secret| {
+            WebhookSecret::new(name(&secret.name), secret.expose(), secret.expires_at)
+        }))
+        .collect();
+    let mut names = HashSet::new();
+    if let Some(secret) = secrets.iter().find(|secret| !names.insert(&secret.name)) {
+        return Err(ConfigError::invalid(
+            "secrets",
+            format!("the name {:?} is used more than once", secret.name),
+        ));
+    }
+    Ok(secrets)
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Exporter {
+    pub protocol: Protocol,
+    pub endpoint: Option<Url>,
+    pub headers: HashMap<String, String>,
+    /// The export timeout in milliseconds.
+    pub timeout: Option<u64>,
+    pub tls: Tls,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    pub certificate: Option<PathBuf>,
+    pub client_certificate: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub domain_name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// The largest accepted request body, in bytes.
+    pub max_body_size: usize,
+    /// How long a request may take, in seconds.
+    pub request_timeout: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_body_size: middleware::DEFAULT_MAX_BODY_SIZE,
+            request_timeout: 10,
+        }
+    }
+}
+
+/// Access to the CircleCI API, to add what the webhook payloads lack.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Api {
+    pub base_url: Url,
+    /// A personal API token, sent as `Circle-Token`.
+    pub token: Option<Secret>,
+    /// How long the requests about one job may take together, in seconds.
+    /// Must be shorter than `limits.request_timeout`.
+    pub timeout: u64,
+    /// Fetch the steps of completed jobs and export them as spans.
+    pub steps: bool,
+    /// Fetch the test results of completed jobs and summarize them.
+    pub tests: bool,
+    /// Which tests to export as spans.
+    pub test_spans: api::TestSpans,
+    /// List the artifacts of completed jobs and link to them.
+    pub artifacts: bool,
+    /// The most artifacts linked from a job span.
+    pub max_artifacts: usize,
+    /// Link job spans to the spans of the jobs they required.
+    pub dependencies: bool,
+    /// Mark the jobs on the critical path of their workflow. Job spans are
+    /// exported when their workflow completes.
+    pub critical_path: bool,
+    /// How many job spans to hold back at most.
+    pub pending_jobs: usize,
+    /// How long to hold back job spans at most, in seconds.
+    pub pending_timeout: u64,
+}
+
+impl Default for Api {
+    fn default() -> Self {
+        Api {
+            base_url: Url::parse(api::DEFAULT_BASE_URL).unwrap(),
+            token: None,
+            timeout: 5,
+            steps: false,
+            tests: false,
+            test_spans: api::TestSpans::default(),
+            artifacts: false,
+            max_artifacts: 20,
+            dependencies: false,
+            critical_path: false,
+            pending_jobs: critical_path::DEFAULT_CAPACITY,
+            pending_timeout: critical_path::DEFAULT_MAX_AGE.as_secs(),
+        }
+    }
+}
+
+impl Api {
+    /// Whether anything is to be fetched.
+    pub fn is_enabled(&self) -> bool {
+        self.steps || self.tests || self.artifacts || self.dependencies || self.critical_path
+    }
+
+    /// A client if anything is to be fetched.
+    pub fn client(&self) -> Result<Option<api::Client>, ConfigError> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+        let client = api::Client::new(
+            self.base_url.clone(),
+            self.token.as_ref().map(|token| token.expose().to_owned()),
+            Duration::from_secs(self.timeout),
+        )
+        .map_err(|e| ConfigError::invalid("api", e))?;
+        Ok(Some(
+            client
+                .with_steps(self.steps)
+                .with_tests(self.tests, self.test_spans)
+                .with_artifacts(self.artifacts, self.max_artifacts)
+                .with_dependencies(self.dependencies),
+        ))
+    }
+
+    pub fn critical_path(&self) -> Option<CriticalPath> {
+        self.critical_path.then(|| {
+            CriticalPath::new(self.pending_jobs, Duration::from_secs(self.pending_timeout))
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Replay {
+    /// Reject events that happened more than this many seconds ago, or ahead.
+    pub tolerance: Option<u64>,
+    /// How many event ids to remember to detect duplicates. 0 disables it.
+    pub capacity: usize,
+    /// A file to keep the event ids in across restarts.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for Replay {
+    fn default() -> Self {
+        Replay {
+            tolerance: None,
+            capacity: replay::DEFAULT_CAPACITY,
+            path: None,
+        }
+    }
+}
+
+impl Replay {
+    /// Checks that the event ids can be kept at `path`, without reading or
+    /// writing the file, which `replay_guard` does.
+    pub fn check_path(&self) -> Result<(), ConfigError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if path.is_dir() {
+            return Err(ConfigError::invalid("replay.path", "is a directory"));
+        }
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        if !directory.is_dir() {
+            return Err(ConfigError::invalid(
+                "replay.path",
+                format!("the directory {:?} does not exist", directory),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn replay_guard(&self) -> Result<ReplayGuard, ConfigError> {
+        let tolerance = self
+            .tolerance
+            .map(|seconds| chrono::Duration::seconds(seconds as i64));
+        let guard = ReplayGuard::new(tolerance, self.capacity);
+        match &self.path {
+            Some(path) => guard
+                .with_persistence(path)
+                .map_err(|source| ConfigError::Read {
+                    path: path.clone(),
+                    source,
+                }),
+            None => Ok(guard),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    /// Serve the `/traceparent` endpoint for use in CircleCI jobs.
+    pub traceparent: bool,
+    /// Export metrics about the server itself.
+    pub metrics: bool,
+    /// Whether spans carry the OpenTelemetry CI/CD semantic convention
+    /// attributes, the original `circleci.*` ones, or both.
+    pub attributes: AttributeMode,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            traceparent: true,
+            metrics: true,
+            attributes: AttributeMode::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration file from `args`, if any, and applies the
+    /// environment variables and the command line arguments on top.
+    pub fn load(args: &Args) -> Result<Config, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply_env(std::env::vars())?;
+        config.apply_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the settings that depend on each other.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.api.is_enabled() && self.api.timeout >= self.limits.request_timeout {
+            return Err(ConfigError::invalid(
+                "api.timeout",
+                format!(
+                    "must be shorter than limits.request_timeout ({} seconds)",
+                    self.limits.request_timeout
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let content = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+        let parse_error = |message: String| ConfigError::Parse {
+            path: path.to_owned(),
+            message,
+        };
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|e| parse_error(e.to_string())),
+            Some("yaml" | "yml") => {
+                serde_yaml::from_str(&content).map_err(|e| parse_error(e.to_string()))
+            }
+            _ => Err(ConfigError::Format(path.to_owned())),
+        }
+    }
+
+    fn apply_env(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), ConfigError> {
+        let vars: HashMap<String, String> = vars.into_iter().collect();
+
+        if let Some(secret) = vars.get(SECRET_TOKEN) {
+            self.secrets.hook_secret = Some(Secret(secret.clone()));
+        }
+        if let Some(token) = vars.get(API_TOKEN) {
+            self.api.token = Some(Secret(token.clone()));
+        }
+        if let Some(secret) = vars.get(PREVIOUS_SECRET_TOKEN) {
+            self.secrets
+                .previous
+                .push(WebhookSecret::new("previous", secret, None));
+        }
+
+        if let Some((name, endpoint)) = [ENDPOINT, OTEL_EXPORTER_OTLP_ENDPOINT]
+            .iter()
+            .find_map(|name| vars.get(*name).map(|value| (name, value)))
+        {
+            self.exporter.endpoint =
+                Some(Url::parse(endpoint).map_err(|e| ConfigError::invalid(*name, e))?);
+        }
+
+        if let Some(headers) = vars.get(OTEL_EXPORTER_OTLP_HEADERS) {
+            self.exporter.headers.extend(parse_otel_headers(headers));
+        }
+        let mut prefixed: Vec<_> = vars
+            .iter()
+            .filter(|(name, _)| name.starts_with(HEADER_PREFIX) && name.as_str() != ENDPOINT)
+            .map(|(name, value)| {
+                let header_name = name
+                    .strip_prefix(HEADER_PREFIX)
+                    .map(|h| h.replace('_', "-"))
+                    .map(|h| h.to_ascii_lowercase())
+                    .unwrap();
+                (header_name, value.clone())
+            })
+            .collect();
+        prefixed.sort();
+        self.exporter.headers.extend(prefixed);
+
+        if let Some(timeout) = vars.get(OTEL_EXPORTER_OTLP_TIMEOUT) {
+            self.exporter.timeout = Some(
+                timeout
+                    .parse()
+                    .map_err(|e| ConfigError::invalid(OTEL_EXPORTER_OTLP_TIMEOUT, e))?,
+            );
+        }
+
+        // like the OpenTelemetry SDKs, `cicd` switches to the conventions and
+        // `cicd/dup` emits the old attributes too
+        if let Some(opt_in) = vars.get(OTEL_SEMCONV_STABILITY_OPT_IN) {
+            let opt_in: Vec<_> = opt_in.split(',').map(str::trim).collect();
+            if opt_in.contains(&"cicd/dup") {
+                self.features.attributes = AttributeMode::Both;
+            } else if opt_in.contains(&"cicd") {
+                self.features.attributes = AttributeMode::Semconv;
+            }
+        }
+
+        if let Some(service_name) = vars
+            .get(SERVICE_NAME)
+            .or_else(|| vars.get(OTEL_SERVICE_NAME))
+        {
+            self.resource.insert(
+                opentelemetry_semantic_conventions::resource::SERVICE_NAME.to_string(),
+                service_name.clone(),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        if let Some(address) = args.address {
+            self.listen.address = address;
+        }
+        if let Some(port) = args.port {
+            self.listen.port = port;
+        }
+        if let Some(protocol) = args.otlp_protocol {
+            self.exporter.protocol = protocol;
+        }
+        let tls = &mut self.exporter.tls;
+        if let Some(certificate) = &args.tls.otlp_certificate {
+            tls.certificate = Some(certificate.clone());
+        }
+        if let Some(client_certificate) = &args.tls.otlp_client_certificate {
+            tls.client_certificate = Some(client_certificate.clone());
+        }
+        if let Some(client_key) = &args.tls.otlp_client_key {
+            tls.client_key = Some(client_key.clone());
+        }
+        if let Some(domain_name) = &args.tls.otlp_domain_name {
+            tls.domain_name = Some(domain_name.clone());
+        }
+    }
+
+    /// Resolves the exporter settings, reading all referenced files.
+    pub fn exporter_config(&self) -> Result<ExporterConfig, ConfigError> {
+        let exporter = &self.exporter;
+        let endpoint = match &exporter.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => Url::parse(match exporter.protocol {
+                Protocol::Grpc => OTLP_GRPC_ENDPOINT_DEFAULT,
+                Protocol::HttpProtobuf | Protocol::HttpJson => OTLP_HTTP_ENDPOINT_DEFAULT,
+            })
+            .unwrap(),
+        };
+        if endpoint.host_str().is_none() {
+            return Err(ConfigError::invalid(
+                "exporter endpoint",
+                "the url has no host",
+            ));
+        }
+
+        let mut metadata = MetadataMap::new();
+        for (key, value) in &exporter.headers {
+            metadata.insert(
+                MetadataKey::from_str(key)
+                    .map_err(|e| ConfigError::invalid(format!("header name {:?}", key), e))?,
+                MetadataValue::try_from(value)
+                    .map_err(|e| ConfigError::invalid(format!("value of header {:?}", key), e))?,
+            );
+        }
+
+        let tls = &exporter.tls;
+        let uses_tls = tls.certificate.is_some()
+            || tls.client_certificate.is_some()
+            || tls.client_key.is_some()
+            || tls.domain_name.is_some();
+        if uses_tls && endpoint.scheme() != "https" {
+            return Err(ConfigError::invalid(
+                "exporter tls",
+                "the endpoint must be an https:// url to use TLS",
+            ));
+        }
+
+        let read = |path: &PathBuf| {
+            fs::read(path).map_err(|source| ConfigError::Read {
+                path: path.clone(),
+                source,
+            })
+        };
+        let identity = match (&exporter.tls.client_certificate, &exporter.tls.client_key) {
+            (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+            (None, None) => None,
+            _ => {
+                return Err(ConfigError::invalid(
+                    "exporter tls",
+                    "the client certificate and key must be set together",
+                ))
+            }
+        };
+        if exporter.tls.domain_name.is_some() && exporter.protocol != Protocol::Grpc {
+            return Err(ConfigError::invalid(
+                "exporter tls",
+                "overriding the domain name is only supported for grpc",
+            ));
+        }
+
+        Ok(ExporterConfig {
+            protocol: exporter.protocol,
+            endpoint,
+            headers: exporter.headers.clone(),
+            metadata,
+            timeout: exporter
+                .timeout
+                .map(Duration::from_millis)
+                .unwrap_or(Duration::from_secs(OTEL_EXPORTER_OTLP_TIMEOUT_DEFAULT)),
+            tls: TlsConfig {
+                domain_name: exporter.tls.domain_name.clone(),
+                ca_certificate: exporter.tls.certificate.as_ref().map(read).transpose()?,
+                identity,
+            },
+        })
+    }
+
+    pub fn resource(&self) -> Resource {
+        let service_name = opentelemetry_semantic_conventions::resource::SERVICE_NAME;
+        let mut attributes: Vec<KeyValue> = self
+            .resource
+            .iter()
+            .map(|(key, value)| KeyValue::new(key.clone(), value.clone()))
+            .collect();
+        if !self.resource.contains_key(service_name.as_str()) {
+            attributes.push(service_name.string("circleci"));
+        }
+        Resource::new(attributes)
+    }
+}
+
+/// The exporter settings, resolved and ready to use.
+pub struct ExporterConfig {
+    pub protocol: Protocol,
+    pub endpoint: Url,
+    pub headers: HashMap<String, String>,
+    pub metadata: MetadataMap,
+    pub timeout: Duration,
+    pub tls: TlsConfig,
+}
+
+pub struct TlsConfig {
+    pub domain_name: Option<String>,
+    pub ca_certificate: Option<Vec<u8>>,
+    /// The client certificate and key, both PEM encoded.
+    pub identity: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Parses the `key1=value1,key2=value2` format of `OTEL_EXPORTER_OTLP_HEADERS`.
+fn parse_otel_headers(headers: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    headers.split(',').filter_map(|header| {
+        let (key, value) = header.split_once('=')?;
+        let value = percent_decode_str(value.trim()).decode_utf8_lossy();
+        Some((key.trim().to_ascii_lowercase(), value.into_owned()))
+    })
+}
+
+#[cfg(test)]
+mod otel_headers_tests {
+    use super::parse_otel_headers;
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(parse_otel_headers("").count(), 0);
+    }
+
+    #[test]
+    fn test_multiple_headers() {
+        assert_eq!(
+            parse_otel_headers("x-honeycomb-dataset=circleci, X-Honeycomb-Team=a%20b%3Dc")
+                .collect::<Vec<_>>(),
+            vec![
+                ("x-honeycomb-dataset".to_string(), "circleci".to_string()),
+                ("x-honeycomb-team".to_string(), "a b=c".to_string()),
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use std::{net::IpAddr, time::Duration};
+
+    use circleci_hook_app::semconv::AttributeMode;
+    use uuid::Uuid;
+
+    use super::{Config, ConfigError, Protocol, Replay};
+
+    const TOML: &str = r#"
+        [listen]
+        address = "127.0.0.1"
+        port = 8080
+
+        [secrets]
+        hook_secret = "hunter123"
+
+        [[secrets.previous]]
+        name = "2022-09"
+        value = "old-secret"
+        expires_at = "2022-12-01T00:00:00Z"
+
+        [exporter]
+        protocol = "http/json"
+        endpoint = "https://api.honeycomb.io"
+        timeout = 5000
+
+        [exporter.headers]
+        x-honeycomb-team = "from-file"
+
+        [resource]
+        "service.name" = "ci"
+        "deployment.environment" = "production"
+
+        [features]
+        traceparent = false
+
+        [replay]
+        tolerance = 300
+        capacity = 100
+
+        [limits]
+        max_body_size = 65536
+    "#;
+
+    const YAML: &str = r#"
+        listen:
+          port: 8080
+        exporter:
+          protocol: grpc
+          headers:
+            x-honeycomb-team: from-file
+        features:
+          metrics: false
+    "#;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::default();
+        assert_eq!(config.listen.address, "::".parse::<IpAddr>().unwrap());
+        assert_eq!(config.listen.port, 3000);
+        assert!(config.features.traceparent);
+        let exporter = config.exporter_config().unwrap();
+        assert_eq!(exporter.protocol, Protocol::Grpc);
+        assert_eq!(exporter.endpoint.as_str(), "http://localhost:4317/");
+        assert_eq!(exporter.timeout, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_toml() {
+        let config: Config = toml::from_str(TOML).unwrap();
+        assert_eq!(config.listen.port, 8080);
+        assert_eq!(
+            config.secrets.hook_secret.as_ref().unwrap().expose(),
+            "hunter123"
+        );
+        let store = config.secrets.secret_store().unwrap();
+        let secrets = store.secrets_for(None, None).unwrap();
+        assert_eq!(secrets[0].name, "current");
+        assert_eq!(secrets[1].name, "2022-09");
+        assert!(secrets[1].expires_at.is_some());
+        assert!(!config.features.traceparent);
+        assert!(config.features.metrics);
+        assert_eq!(config.resource["deployment.environment"], "production");
+        assert_eq!(config.exporter.protocol, Protocol::HttpJson);
+        assert_eq!(config.replay.tolerance, Some(300));
+        assert_eq!(config.limits.max_body_size, 65536);
+        assert_eq!(config.limits.request_timeout, 10);
+        assert!(config.replay.replay_guard().is_ok());
+    }
+
+    #[test]
+    fn test_replay_path() {
+        let directory = std::env::temp_dir();
+        let path = directory.join(format!("seen-events-{}", Uuid::new_v4()));
+        let replay = Replay {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+        assert!(replay.check_path().is_ok());
+        assert!(!path.exists());
+
+        let replay = Replay {
+            path: Some(path.join("seen-events")),
+            ..Default::default()
+        };
+        assert!(replay.check_path().is_err());
+        let replay = Replay {
+            path: Some(directory),
+            ..Default::default()
+        };
+        assert!(replay.check_path().is_err());
+    }
+
+    #[test]
+    fn test_yaml() {
+        let config: Config = serde_yaml::from_str(YAML).unwrap();
+        assert_eq!(config.listen.port, 8080);
+        assert_eq!(config.exporter.headers["x-honeycomb-team"], "from-file");
+        assert!(!config.features.metrics);
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        assert!(toml::from_str::<Config>("[listen]\nhost = \"localhost\"").is_err());
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let mut config: Config = toml::from_str(TOML).unwrap();
+        config
+            .apply_env(env(&[
+                ("OTEL_EXPORTER_OTLP_ENDPOINT", "https://otel.example.com"),
+                ("CIRCLECI_OTLP_ENDPOINT", "https://circleci.example.com"),
+                (
+                    "OTEL_EXPORTER_OTLP_HEADERS",
+                    "x-honeycomb-team=otel,x-other=1",
+                ),
+                ("CIRCLECI_OTLP_X_HONEYCOMB_TEAM", "circleci"),
+                ("OTEL_SERVICE_NAME", "otel-service"),
+                ("CIRCLECI_HOOK_SECRET", "from-env"),
+                ("CIRCLECI_HOOK_SECRET_PREVIOUS", "previous-from-env"),
+            ]))
+            .unwrap();
+        assert_eq!(
+            config.secrets.hook_secret.as_ref().unwrap().expose(),
+            "from-env"
+        );
+        let store = config.secrets.secret_store().unwrap();
+        let names: Vec<_> = store
+            .secrets_for(None, None)
+            .unwrap()
+            .iter()
+            .map(|secret| secret.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["current", "2022-09", "previous"]);
+        assert_eq!(config.resource["service.name"], "otel-service");
+
+        let exporter = config.exporter_config().unwrap();
+        assert_eq!(exporter.endpoint.as_str(), "https://circleci.example.com/");
+        assert_eq!(exporter.headers["x-honeycomb-team"], "circleci");
+        assert_eq!(exporter.headers["x-other"], "1");
+        assert!(!exporter.headers.contains_key("endpoint"));
+        assert_eq!(exporter.timeout, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_api() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [api]
+            base_url = "http://localhost:8080/"
+            steps = true
+            tests = true
+            artifacts = true
+            max_artifacts = 5
+            dependencies = true
+            critical_path = true
+            pending_jobs = 10
+            pending_timeout = 600
+
+            [api.test_spans]
+            failed = true
+            slower_than = 30
+            "#,
+        )
+        .unwrap();
+        config
+            .apply_env(env(&[("CIRCLECI_TOKEN", "token-from-env")]))
+            .unwrap();
+        assert_eq!(
+            config.api.token.as_ref().unwrap().expose(),
+            "token-from-env"
+        );
+        let client = config.api.client().unwrap().unwrap();
+        assert!(!format!("{:?}", client).contains("token-from-env"));
+        assert!(format!("{:?}", client).contains("artifacts: Some(5)"));
+        assert!(format!("{:?}", client).contains("dependencies: true"));
+        assert!(config.api.critical_path().is_some());
+        assert!(Config::default().api.client().unwrap().is_none());
+        assert!(Config::default().api.critical_path().is_none());
+    }
+
+    #[test]
+    fn test_api_timeout() {
+        assert!(Config::default().validate().is_ok());
+        let config: Config = toml::from_str("[api]\nsteps = true\ntimeout = 10").unwrap();
+        assert!(config.validate().is_err());
+        // without the API, its timeout doesn't matter
+        let config: Config = toml::from_str("[limits]\nrequest_timeout = 5").unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_semconv_opt_in() {
+        let mut config = Config::default();
+        config
+            .apply_env(env(&[("OTEL_SEMCONV_STABILITY_OPT_IN", "http, cicd")]))
+            .unwrap();
+        assert_eq!(config.features.attributes, AttributeMode::Semconv);
+        config
+            .apply_env(env(&[("OTEL_SEMCONV_STABILITY_OPT_IN", "cicd/dup")]))
+            .unwrap();
+        assert_eq!(config.features.attributes, AttributeMode::Both);
+
+        let config: Config = toml::from_str("[features]\nattributes = \"legacy\"").unwrap();
+        assert_eq!(config.features.attributes, AttributeMode::Legacy);
+    }
+
+    #[test]
+    fn test_duplicate_secret_names() {
+        let mut config: Config = toml::from_str(TOML).unwrap();
+        config
+            .secrets
+            .previous
+            .push(config.secrets.previous[0].clone());
+        assert!(config.secrets.secret_store().is_err());
+    }
+
+    #[test]
+    fn test_keyed_secrets() {
+        let config: Config = toml::from_str(
+            r#"
+            [secrets]
+            unknown_webhooks = "reject"
+
+            [[secrets.webhooks]]
+            webhook_id = "2f9a4f0e-3c0c-4f43-9a6b-3c1b0b6c2d5e"
+            hook_secret = "team-a"
+
+            [[secrets.webhooks]]
+            project_slug = "gh/example/b"
+            hook_secret = "team-b"
+            previous = [{ name = "old", value = "team-b-old" }]
+        "#,
+        )
+        .unwrap();
+        let store = config.secrets.secret_store().unwrap();
+        let names = |project_slug| -> Option<Vec<String>> {
+            store
+                .secrets_for(None, Some(project_slug))
+                .map(|secrets| secrets.iter().map(|s| s.name.clone()).collect())
+        };
+        assert_eq!(
+            names("gh/example/b"),
+            Some(vec![
+                "gh/example/b/current".to_string(),
+                "gh/example/b/old".to_string()
+            ])
+        );
+        assert_eq!(names("gh/example/c"), None);
+        // the payloads carry the long form
+        assert_eq!(names("github/Example/B").map(|names| names.len()), Some(2));
+    }
+
+    #[test]
+    fn test_keyed_secrets_duplicate_slug() {
+        let config: Config = toml::from_str(
+            r#"
+            [secrets]
+            unknown_webhooks = "reject"
+
+            [[secrets.webhooks]]
+            project_slug = "gh/example/b"
+            hook_secret = "team-b"
+
+            [[secrets.webhooks]]
+            project_slug = "github/Example/b"
+            hook_secret = "team-b-again"
+        "#,
+        )
+        .unwrap();
+        assert!(config.secrets.secret_store().is_err());
+    }
+
+    #[test]
+    fn test_keyed_secrets_need_one_key() {
+        let config: Config = toml::from_str(
+            r#"
+            [secrets]
+            unknown_webhooks = "reject"
+
+            [[secrets.webhooks]]
+            hook_secret = "team-a"
+        "#,
+        )
+        .unwrap();
+        assert!(config.secrets.secret_store().is_err());
+    }
+
+    #[test]
+    fn test_keyed_secrets_without_default() {
+        let keyed = r#"
+            [[secrets.webhooks]]
+            project_slug = "gh/example/b"
+            hook_secret = "team-b"
+        "#;
+        // unknown webhooks would be accepted unsigned
+        let config: Config = toml::from_str(keyed).unwrap();
+        assert!(config.secrets.secret_store().is_err());
+
+        let config: Config =
+            toml::from_str(&format!("[secrets]\nhook_secret = \"default\"\n{}", keyed)).unwrap();
+        assert!(config.secrets.secret_store().is_ok());
+        let config: Config = toml::from_str(&format!(
+            "[secrets]\nunknown_webhooks = \"reject\"\n{}",
+            keyed
+        ))
+        .unwrap();
+        assert!(config.secrets.secret_store().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_env() {
+        let mut config = Config::default();
+        assert!(config
+            .apply_env(env(&[("OTEL_EXPORTER_OTLP_TIMEOUT", "10s")]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_http_default_endpoint() {
+        let mut config = Config::default();
+        config.exporter.protocol = Protocol::HttpProtobuf;
+        let exporter = config.exporter_config().unwrap();
+        assert_eq!(exporter.endpoint.as_str(), "http://localhost:4318/");
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let mut config = Config::default();
+        config
+            .exporter
+            .headers
+            .insert("x invalid".to_string(), "value".to_string());
+        assert!(config.exporter_config().is_err());
+    }
+
+    #[test]
+    fn test_missing_certificate() {
+        let mut config = Config::default();
+        config.exporter.endpoint = Some("https://collector.example.com".parse().unwrap());
+        config.exporter.tls.certificate = Some("/does/not/exist.pem".into());
+        assert!(matches!(
+            config.exporter_config(),
+            Err(ConfigError::Read { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tls_needs_https() {
+        let mut config = Config::default();
+        config.exporter.tls.certificate = Some("/does/not/exist.pem".into());
+        assert!(matches!(
+            config.exporter_config(),
+            Err(ConfigError::Invalid { .. })
+        ));
+
+        let mut config = Config::default();
+        config.exporter.endpoint = Some("http://collector:4317".parse().unwrap());
+        config.exporter.tls.domain_name = Some("collector.internal".to_string());
+        assert!(config.exporter_config().is_err());
+    }
+
+    #[test]
+    fn test_secret_is_redacted() {
+        let config: Config = toml::from_str(TOML).unwrap();
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("hunter123"));
+        assert!(!debug.contains("old-secret"));
+    }
+}
@@ -1,140 +1,373 @@
 use axum::{
     body::Bytes,
     extract::{Path, State},
-    http::{HeaderMap, StatusCode},
+    http::StatusCode,
     response::{IntoResponse, Response},
     routing::{get, post},
     Router,
 };
-use circleci_hook_app::{handle_hook, header_value_from_map, translate_traceparent};
-use opentelemetry::{
-    sdk::{trace as sdktrace, Resource},
-    trace::TraceError,
-    KeyValue,
+use circleci_hook_app::{
+    api,
+    critical_path::CriticalPath,
+    handle_hook,
+    middleware::{JsonBodyLayer, SignatureLayer},
+    signatures::SecretStore,
+    translate_traceparent, Hook,
 };
-use opentelemetry_otlp::WithExportConfig;
-use std::{env, str::FromStr};
-use tonic::{
-    metadata::{MetadataKey, MetadataMap},
-    transport::ClientTlsConfig,
+use clap::Parser;
+use config::{Args, Config, ConfigError, ExporterConfig, Protocol};
+use opentelemetry::{
+    global, metrics,
+    sdk::{
+        export::metrics::aggregation,
+        metrics::{controllers::BasicController, selectors},
+        trace as sdktrace, Resource,
+    },
+    trace::{TraceError, TracerProvider as _},
+    Context,
 };
+use opentelemetry_otlp::{TonicExporterBuilder, WithExportConfig};
+use otlp_http::{Encoding, HttpExporter};
+use std::{net::SocketAddr, process, sync::Arc, time::Duration};
+use tokio::signal;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity};
 use tower::ServiceBuilder;
-use tower_http::trace::TraceLayer;
+use tower_http::{timeout::TimeoutLayer, trace::TraceLayer};
 use tracing::{debug, info, instrument, log};
-use url::Url;
 use uuid::Uuid;
 
+mod config;
+mod otlp_http;
+
 #[derive(Clone, Debug)]
 struct AppState {
-    tracer: sdktrace::Tracer,
+    hook: Arc<Hook>,
 }
 
-const ENDPOINT: &str = "CIRCLECI_OTLP_ENDPOINT";
-const HEADER_PREFIX: &str = "CIRCLECI_OTLP_";
-const SECRET_TOKEN: &str = "CIRCLECI_HOOK_SECRET";
-const SERVICE_NAME: &str = "CIRCLECI_HOOK_SERVICE";
+fn exporter(config: &ExporterConfig) -> TonicExporterBuilder {
+    let builder = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(config.endpoint.as_str())
+        .with_metadata(config.metadata.clone())
+        .with_timeout(config.timeout);
+    if config.endpoint.scheme() != "https" {
+        return builder;
+    }
 
-fn init_tracer() -> Result<sdktrace::Tracer, TraceError> {
-    let endpoint = env::var(ENDPOINT).unwrap_or_else(|_| {
-        panic!(
-            "You must specify and endpoint to connect to with the variable {:?}.",
-            ENDPOINT
-        )
-    });
-    let endpoint = Url::parse(&endpoint).expect("endpoint is not a valid url");
-    env::remove_var(ENDPOINT);
-    let mut metadata = MetadataMap::new();
-    for (key, value) in env::vars()
-        .filter(|(name, _)| name.starts_with(HEADER_PREFIX))
-        .map(|(name, value)| {
-            let header_name = name
-                .strip_prefix(HEADER_PREFIX)
-                .map(|h| h.replace('_', "-"))
-                .map(|h| h.to_ascii_lowercase())
-                .unwrap();
-            (header_name, value)
-        })
-    {
-        metadata.insert(MetadataKey::from_str(&key).unwrap(), value.parse().unwrap());
+    let mut tls_config = ClientTlsConfig::new().domain_name(
+        config.tls.domain_name.as_deref().unwrap_or_else(|| {
+            config
+                .endpoint
+                .host_str()
+                .expect("the specified endpoint should have a valid host")
+        }),
+    );
+    if let Some(ca_certificate) = &config.tls.ca_certificate {
+        tls_config = tls_config.ca_certificate(Certificate::from_pem(ca_certificate));
     }
+    if let Some((cert, key)) = &config.tls.identity {
+        tls_config = tls_config.identity(Identity::from_pem(cert, key));
+    }
+    builder.with_tls_config(tls_config)
+}
 
-    opentelemetry_otlp::new_pipeline()
-        .tracing()
-        .with_exporter(
-            opentelemetry_otlp::new_exporter()
-                .tonic()
-                .with_endpoint(endpoint.as_str())
-                .with_metadata(metadata)
-                .with_tls_config(
-                    ClientTlsConfig::new().domain_name(
-                        endpoint
-                            .host_str()
-                            .expect("the specified endpoint should have a valid host"),
-                    ),
-                ),
+fn http_exporter(config: &ExporterConfig, encoding: Encoding) -> Result<HttpExporter, TraceError> {
+    let mut builder = reqwest::Client::builder().timeout(config.timeout);
+    if let Some(ca_certificate) = &config.tls.ca_certificate {
+        builder = builder.add_root_certificate(
+            reqwest::Certificate::from_pem(ca_certificate)
+                .map_err(|e| TraceError::from(e.to_string()))?,
+        );
+    }
+    if let Some((cert, key)) = &config.tls.identity {
+        builder = builder.identity(
+            reqwest::Identity::from_pem(&[cert.as_slice(), key.as_slice()].concat())
+                .map_err(|e| TraceError::from(e.to_string()))?,
+        );
+    }
+    let client = builder
+        .build()
+        .map_err(|e| TraceError::from(e.to_string()))?;
+    HttpExporter::new(client, &config.endpoint, &config.headers, encoding)
+}
+
+fn init_tracer(
+    config: &ExporterConfig,
+    resource: Resource,
+) -> Result<sdktrace::Tracer, TraceError> {
+    let encoding = match config.protocol {
+        Protocol::Grpc => {
+            return opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(exporter(config))
+                .with_trace_config(sdktrace::config().with_resource(resource))
+                .install_batch(opentelemetry::runtime::Tokio);
+        }
+        Protocol::HttpProtobuf => Encoding::Protobuf,
+        Protocol::HttpJson => Encoding::Json,
+    };
+    let provider = sdktrace::TracerProvider::builder()
+        .with_batch_exporter(
+            http_exporter(config, encoding)?,
+            opentelemetry::runtime::Tokio,
         )
-        .with_trace_config(
-            sdktrace::config().with_resource(Resource::new(vec![KeyValue::new(
-                opentelemetry_semantic_conventions::resource::SERVICE_NAME,
-                env::var(SERVICE_NAME).unwrap_or_else(|_| "circleci".to_string()),
-            )])),
+        .with_config(sdktrace::config().with_resource(resource))
+        .build();
+    let tracer = provider.versioned_tracer(
+        env!("CARGO_PKG_NAME"),
+        Some(env!("CARGO_PKG_VERSION")),
+        None,
+    );
+    global::set_tracer_provider(provider);
+    Ok(tracer)
+}
+
+/// `opentelemetry-otlp` can only export metrics over gRPC.
+fn init_meter(
+    config: &ExporterConfig,
+    resource: Resource,
+) -> metrics::Result<Option<BasicController>> {
+    if config.protocol != Protocol::Grpc {
+        info!("Metrics are only exported over grpc");
+        return Ok(None);
+    }
+    opentelemetry_otlp::new_pipeline()
+        .metrics(
+            selectors::simple::inexpensive(),
+            aggregation::cumulative_temporality_selector(),
+            opentelemetry::runtime::Tokio,
         )
-        .install_batch(opentelemetry::runtime::Tokio)
+        .with_exporter(exporter(config))
+        .with_resource(resource)
+        .build()
+        .map(Some)
+}
+
+/// The configuration with everything that can fail resolved, so that
+/// `--check-config` catches it. The replay guard is left out, as it rewrites
+/// its file; only its path is checked.
+struct Resolved {
+    config: Config,
+    exporter: ExporterConfig,
+    secrets: SecretStore,
+    api: Option<api::Client>,
+    critical_path: Option<CriticalPath>,
+}
+
+fn load_config(args: &Args) -> Result<Resolved, ConfigError> {
+    let config = Config::load(args)?;
+    config.replay.check_path()?;
+    Ok(Resolved {
+        exporter: config.exporter_config()?,
+        secrets: config.secrets.secret_store()?,
+        api: config.api.client()?,
+        critical_path: config.api.critical_path(),
+        config,
+    })
 }
 
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
     tracing_subscriber::fmt::init();
 
-    let tracer = init_tracer().expect("build an OTLP tracer");
-    let state = AppState { tracer };
+    let Resolved {
+        config,
+        exporter: exporter_config,
+        secrets,
+        api,
+        critical_path,
+    } = match load_config(&args) {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            eprintln!("Invalid configuration: {}", error);
+            process::exit(1);
+        }
+    };
+    if args.check_config {
+        println!("Configuration is valid");
+        return;
+    }
+    let replay = match config.replay.replay_guard() {
+        Ok(replay) => replay,
+        Err(error) => {
+            eprintln!("Invalid configuration: {}", error);
+            process::exit(1);
+        }
+    };
 
-    let app = Router::with_state(state)
-        .route("/", get(root))
-        .route("/", post(hook_handler))
-        .route(
-            "/traceparent/:workflow_id/:job_id",
-            get(traceparent_handler),
-        )
-        .layer(
-            ServiceBuilder::new()
-                // .layer(middleware::from_fn(validate_circleci_signature))
-                .layer(TraceLayer::new_for_http()),
-        );
+    let tracer = init_tracer(&exporter_config, config.resource()).expect("build an OTLP tracer");
+    let meter = if config.features.metrics {
+        init_meter(&exporter_config, config.resource()).expect("build an OTLP meter")
+    } else {
+        None
+    };
+    let mut hook = Hook::new(tracer)
+        .with_replay(replay)
+        .with_attributes(config.features.attributes);
+    if let Some(api) = api {
+        hook = hook.with_api(api);
+    }
+    if let Some(critical_path) = critical_path {
+        hook = hook.with_critical_path(critical_path);
+    }
+    let hook = Arc::new(hook);
+    let app = app(hook.clone(), secrets, &config);
 
-    let addr = "[::]:3000".parse().unwrap();
+    let addr = SocketAddr::new(config.listen.address, config.listen.port);
     info!("listening on {}", addr);
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+
+    info!("flushing telemetry");
+    hook.flush();
+    if let Some(Err(error)) = meter.map(|meter| meter.stop(&Context::current())) {
+        log::error!("Error stopping the meter: {:?}", error);
+    }
+    // shutting down the provider blocks until the batch processor has exported everything
+    tokio::task::spawn_blocking(global::shutdown_tracer_provider)
         .await
         .unwrap();
 }
 
+fn app(hook: Arc<Hook>, secrets: SecretStore, config: &Config) -> Router<AppState> {
+    let signatures =
+        SignatureLayer::new(Arc::new(secrets)).with_max_body_size(config.limits.max_body_size);
+    let mut app = Router::with_state(AppState { hook })
+        .route("/", get(root))
+        .route(
+            "/",
+            post(hook_handler)
+                .layer(signatures)
+                .layer(JsonBodyLayer::new(config.limits.max_body_size)),
+        );
+    if config.features.traceparent {
+        app = app.route(
+            "/traceparent/:workflow_id/:job_id",
+            get(traceparent_handler),
+        );
+    }
+    app.layer(
+        ServiceBuilder::new()
+            .layer(TraceLayer::new_for_http())
+            .layer(TimeoutLayer::new(Duration::from_secs(
+                config.limits.request_timeout,
+            ))),
+    )
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install the SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install the SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("shutting down");
+}
+
 async fn root() -> &'static str {
     "Hello, Mikey and backendsouls!"
 }
 
-#[instrument]
-async fn hook_handler(State(state): State<AppState>, headers: HeaderMap, body: Bytes) -> Response {
+#[instrument(skip_all)]
+async fn hook_handler(State(state): State<AppState>, body: Bytes) -> Response {
     debug!("Received request");
-    let hook_result = handle_hook(
-        header_value_from_map(&headers),
-        env::var(SECRET_TOKEN).ok(),
-        body.as_ref(),
-        &state.tracer,
-    )
-    .await;
-    match hook_result {
+    match handle_hook(&state.hook, body.as_ref()).await {
         Ok(msg) => (StatusCode::OK, msg).into_response(),
-        Err(error) => {
-            log::error!("Error processing request: {:?}", error);
-            (StatusCode::NOT_ACCEPTABLE, "Not Acceptable").into_response()
-        }
+        Err(error) => error.into_response(),
     }
 }
 
-#[instrument]
+#[instrument(skip_all)]
 async fn traceparent_handler(Path((workflow_id, job_id)): Path<(Uuid, Uuid)>) -> String {
     debug!("Received request");
     translate_traceparent(workflow_id, job_id)
 }
+
+#[cfg(test)]
+mod handler_tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{body::Body, http::Request};
+    use circleci_hook_app::{
+        signatures::{SecretStore, UnknownWebhookPolicy, WebhookSecret},
+        testing::{sign, TestTracer},
+        Hook,
+    };
+    use tower::ServiceExt;
+    use tracing::Level;
+
+    use crate::{app, config::Config};
+
+    /// Collects the log output of a test.
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signature_not_logged() {
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let tracer = TestTracer::new();
+        let hook = Arc::new(Hook::new(tracer.tracer().clone()));
+        let secrets = SecretStore::new(
+            vec![WebhookSecret::new("current", "hunter123", None)],
+            UnknownWebhookPolicy::Default,
+        );
+        let body = include_bytes!("../app/testdata/ping.json");
+        let signature = sign("hunter123", body);
+        let request = Request::post("/")
+            .header("content-type", "application/json")
+            .header("circleci-signature", &signature)
+            .body(Body::from(&body[..]))
+            .unwrap();
+        app(hook, secrets, &Config::default())
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("hook_handler"), "{}", output);
+        assert!(!output.contains(&signature[3..]), "{}", output);
+        assert!(!output.contains("hunter123"), "{}", output);
+    }
+}
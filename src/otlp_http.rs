@@ -0,0 +1,371 @@
+//! A span exporter for OTLP over HTTP, supporting both the binary protobuf
+//! and the JSON encoding. `opentelemetry-otlp` only ships the former, and only
+//! through `opentelemetry-http`.
+
+use std::collections::HashMap;
+
+use futures::future::BoxFuture;
+use opentelemetry::{
+    sdk::export::trace::{ExportResult, SpanData, SpanExporter},
+    trace::TraceError,
+};
+use opentelemetry_proto::tonic::{
+    collector::trace::v1::ExportTraceServiceRequest,
+    common::v1::{any_value, AnyValue, InstrumentationLibrary, KeyValue},
+    resource::v1::Resource,
+    trace::v1::{span, InstrumentationLibrarySpans, ResourceSpans, Span, Status},
+};
+use prost::Message;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE},
+    Client,
+};
+use serde_json::{json, Value};
+use url::Url;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Protobuf,
+    Json,
+}
+
+#[derive(Debug)]
+pub struct HttpExporter {
+    client: Client,
+    endpoint: Url,
+    headers: HeaderMap,
+    encoding: Encoding,
+}
+
+impl HttpExporter {
+    /// Creates an exporter sending to the `v1/traces` path below `endpoint`.
+    pub fn new(
+        client: Client,
+        endpoint: &Url,
+        headers: &HashMap<String, String>,
+        encoding: Encoding,
+    ) -> Result<Self, TraceError> {
+        let mut endpoint = endpoint.clone();
+        endpoint
+            .path_segments_mut()
+            .map_err(|_| TraceError::from("the OTLP endpoint is not a valid base url"))?
+            .pop_if_empty()
+            .extend(["v1", "traces"]);
+
+        let mut header_map = HeaderMap::new();
+        for (key, value) in headers {
+            header_map.insert(
+                HeaderName::try_from(key).map_err(|e| TraceError::from(e.to_string()))?,
+                HeaderValue::try_from(value).map_err(|e| TraceError::from(e.to_string()))?,
+            );
+        }
+
+        Ok(HttpExporter {
+            client,
+            endpoint,
+            headers: header_map,
+            encoding,
+        })
+    }
+}
+
+impl SpanExporter for HttpExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let request = ExportTraceServiceRequest {
+            resource_spans: batch.into_iter().map(Into::into).collect(),
+        };
+        let (content_type, body) = match self.encoding {
+            Encoding::Protobuf => ("application/x-protobuf", request.encode_to_vec()),
+            Encoding::Json => (
+                "application/json",
+                request_to_json(&request).to_string().into(),
+            ),
+        };
+        let request = self
+            .client
+            .post(self.endpoint.clone())
+            .headers(self.headers.clone())
+            .header(CONTENT_TYPE, content_type)
+            .body(body);
+
+        Box::pin(async move {
+            request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| TraceError::from(e.to_string()))?;
+            Ok(())
+        })
+    }
+}
+
+// The JSON encoding follows the proto3 JSON mapping with the OTLP specific
+// exceptions: ids are hex strings instead of base64 and enums are integers.
+// See https://github.com/open-telemetry/opentelemetry-specification/blob/main/specification/protocol/otlp.md#json-protobuf-encoding
+
+fn request_to_json(request: &ExportTraceServiceRequest) -> Value {
+    json!({
+        "resourceSpans": request.resource_spans.iter().map(resource_spans_to_json).collect::<Vec<_>>(),
+    })
+}
+
+fn resource_spans_to_json(resource_spans: &ResourceSpans) -> Value {
+    json!({
+        "resource": resource_spans.resource.as_ref().map(resource_to_json),
+        "scopeSpans": resource_spans
+            .instrumentation_library_spans
+            .iter()
+            .map(scope_spans_to_json)
+            .collect::<Vec<_>>(),
+        "schemaUrl": resource_spans.schema_url,
+    })
+}
+
+fn resource_to_json(resource: &Resource) -> Value {
+    json!({
+        "attributes": attributes_to_json(&resource.attributes),
+        "droppedAttributesCount": resource.dropped_attributes_count,
+    })
+}
+
+fn scope_spans_to_json(scope_spans: &InstrumentationLibrarySpans) -> Value {
+    json!({
+        "scope": scope_spans.instrumentation_library.as_ref().map(scope_to_json),
+        "spans": scope_spans.spans.iter().map(span_to_json).collect::<Vec<_>>(),
+        "schemaUrl": scope_spans.schema_url,
+    })
+}
+
+fn scope_to_json(scope: &InstrumentationLibrary) -> Value {
+    json!({
+        "name": scope.name,
+        "version": scope.version,
+    })
+}
+
+fn span_to_json(span: &Span) -> Value {
+    json!({
+        "traceId": hex::encode(&span.trace_id),
+        "spanId": hex::encode(&span.span_id),
+        "traceState": span.trace_state,
+        "parentSpanId": hex::encode(&span.parent_span_id),
+        "name": span.name,
+        "kind": span.kind,
+        "startTimeUnixNano": span.start_time_unix_nano.to_string(),
+        "endTimeUnixNano": span.end_time_unix_nano.to_string(),
+        "attributes": attributes_to_json(&span.attributes),
+        "droppedAttributesCount": span.dropped_attributes_count,
+        "events": span.events.iter().map(event_to_json).collect::<Vec<_>>(),
+        "droppedEventsCount": span.dropped_events_count,
+        "links": span.links.iter().map(link_to_json).collect::<Vec<_>>(),
+        "droppedLinksCount": span.dropped_links_count,
+        "status": span.status.as_ref().map(status_to_json),
+    })
+}
+
+fn event_to_json(event: &span::Event) -> Value {
+    json!({
+        "timeUnixNano": event.time_unix_nano.to_string(),
+        "name": event.name,
+        "attributes": attributes_to_json(&event.attributes),
+        "droppedAttributesCount": event.dropped_attributes_count,
+    })
+}
+
+fn link_to_json(link: &span::Link) -> Value {
+    json!({
+        "traceId": hex::encode(&link.trace_id),
+        "spanId": hex::encode(&link.span_id),
+        "traceState": link.trace_state,
+        "attributes": attributes_to_json(&link.attributes),
+        "droppedAttributesCount": link.dropped_attributes_count,
+    })
+}
+
+fn status_to_json(status: &Status) -> Value {
+    json!({
+        "message": status.message,
+        "code": status.code,
+    })
+}
+
+fn attributes_to_json(attributes: &[KeyValue]) -> Vec<Value> {
+    attributes
+        .iter()
+        .map(|kv| {
+            json!({
+                "key": kv.key,
+                "value": kv.value.as_ref().map(any_value_to_json),
+            })
+        })
+        .collect()
+}
+
+fn any_value_to_json(value: &AnyValue) -> Value {
+    match &value.value {
+        Some(any_value::Value::StringValue(v)) => json!({ "stringValue": v }),
+        Some(any_value::Value::BoolValue(v)) => json!({ "boolValue": v }),
+        Some(any_value::Value::IntValue(v)) => json!({ "intValue": v.to_string() }),
+        Some(any_value::Value::DoubleValue(v)) => json!({ "doubleValue": v }),
+        Some(any_value::Value::ArrayValue(v)) => json!({
+            "arrayValue": {
+                "values": v.values.iter().map(any_value_to_json).collect::<Vec<_>>(),
+            },
+        }),
+        Some(any_value::Value::KvlistValue(v)) => json!({
+            "kvlistValue": {
+                "values": attributes_to_json(&v.values),
+            },
+        }),
+        // the SDK never records byte arrays, so these are sent as empty values
+        Some(any_value::Value::BytesValue(_)) | None => json!({}),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        borrow::Cow,
+        collections::HashMap,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    use axum::{
+        body::Bytes,
+        extract::State,
+        http::{HeaderMap, Uri},
+        routing::post,
+        Router,
+    };
+    use opentelemetry::{
+        sdk::{
+            export::trace::{SpanData, SpanExporter},
+            trace::{EvictedHashMap, EvictedQueue},
+            InstrumentationLibrary, Resource,
+        },
+        trace::{SpanContext, SpanId, SpanKind, Status, TraceFlags, TraceId, TraceState},
+        KeyValue,
+    };
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+    use prost::Message;
+    use url::Url;
+
+    use super::{Encoding, HttpExporter};
+
+    type Received = Arc<Mutex<Vec<(Uri, HeaderMap, Bytes)>>>;
+
+    /// Starts a stub OTLP/HTTP receiver recording all requests it gets.
+    async fn stub_receiver() -> (Url, Received) {
+        let received = Received::default();
+        let app =
+            Router::with_state(received.clone()).route(
+                "/*path",
+                post(
+                    |State(received): State<Received>,
+                     uri: Uri,
+                     headers: HeaderMap,
+                     body: Bytes| async move {
+                        received.lock().unwrap().push((uri, headers, body));
+                    },
+                ),
+            );
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let url = Url::parse(&format!("http://{}/otlp", server.local_addr())).unwrap();
+        tokio::spawn(server);
+        (url, received)
+    }
+
+    fn span_data() -> SpanData {
+        let mut attributes = EvictedHashMap::new(16, 1);
+        attributes.insert(KeyValue::new("circleci.job.number", 10));
+        SpanData {
+            span_context: SpanContext::new(
+                TraceId::from_hex("410c427b40a84bb49d425561f5bce5ba").unwrap(),
+                SpanId::from_hex("20e45d7ee4a74aa3").unwrap(),
+                TraceFlags::SAMPLED,
+                false,
+                TraceState::default(),
+            ),
+            parent_span_id: SpanId::from_hex("410c427b40a84bb4").unwrap(),
+            span_kind: SpanKind::Internal,
+            name: Cow::from("job: rust/lint-test-build"),
+            start_time: SystemTime::UNIX_EPOCH + Duration::from_secs(1661631943),
+            end_time: SystemTime::UNIX_EPOCH + Duration::from_secs(1661631991),
+            attributes,
+            events: EvictedQueue::new(0),
+            links: EvictedQueue::new(0),
+            status: Status::Ok,
+            resource: Cow::Owned(Resource::new(vec![KeyValue::new(
+                "service.name",
+                "circleci",
+            )])),
+            instrumentation_lib: InstrumentationLibrary::new("test", None, None),
+        }
+    }
+
+    fn exporter(endpoint: &Url, encoding: Encoding) -> HttpExporter {
+        let headers = HashMap::from([("x-honeycomb-team".to_string(), "secret".to_string())]);
+        HttpExporter::new(reqwest::Client::new(), endpoint, &headers, encoding).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_protobuf() {
+        let (endpoint, received) = stub_receiver().await;
+        exporter(&endpoint, Encoding::Protobuf)
+            .export(vec![span_data()])
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        let (uri, headers, body) = &received[0];
+        assert_eq!(uri.path(), "/otlp/v1/traces");
+        assert_eq!(headers["content-type"], "application/x-protobuf");
+        assert_eq!(headers["x-honeycomb-team"], "secret");
+        let request = ExportTraceServiceRequest::decode(body.clone()).unwrap();
+        let span = &request.resource_spans[0].instrumentation_library_spans[0].spans[0];
+        assert_eq!(span.name, "job: rust/lint-test-build");
+        assert_eq!(hex::encode(&span.span_id), "20e45d7ee4a74aa3");
+    }
+
+    #[tokio::test]
+    async fn test_json() {
+        let (endpoint, received) = stub_receiver().await;
+        exporter(&endpoint, Encoding::Json)
+            .export(vec![span_data()])
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        let (uri, headers, body) = &received[0];
+        assert_eq!(uri.path(), "/otlp/v1/traces");
+        assert_eq!(headers["content-type"], "application/json");
+        let request: serde_json::Value = serde_json::from_slice(body).unwrap();
+        let resource = &request["resourceSpans"][0]["resource"];
+        assert_eq!(resource["attributes"][0]["key"], "service.name");
+        assert_eq!(
+            resource["attributes"][0]["value"]["stringValue"],
+            "circleci"
+        );
+        let span = &request["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "410c427b40a84bb49d425561f5bce5ba");
+        assert_eq!(span["spanId"], "20e45d7ee4a74aa3");
+        assert_eq!(span["parentSpanId"], "410c427b40a84bb4");
+        assert_eq!(span["name"], "job: rust/lint-test-build");
+        assert_eq!(span["startTimeUnixNano"], "1661631943000000000");
+        assert_eq!(span["attributes"][0]["key"], "circleci.job.number");
+        assert_eq!(span["attributes"][0]["value"]["intValue"], "10");
+        assert_eq!(span["status"]["code"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable() {
+        let endpoint = Url::parse("http://127.0.0.1:9/").unwrap();
+        assert!(exporter(&endpoint, Encoding::Json)
+            .export(vec![span_data()])
+            .await
+            .is_err());
+    }
+}